@@ -0,0 +1,5496 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{self};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use flate2::{write::GzEncoder, Compression};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uqbar_process_lib::{
+    get_payload,
+    http::{bind_http_path, send_ws_push, StatusCode, WsMessageType},
+    print_to_terminal,
+    vfs::{create_drive, open_file},
+    Address, Payload, ProcessId, Request,
+};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+
+use crate::state::*;
+
+// Requests are parsed one at a time and never stored, so a large `Send` costs nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum ChatRequest {
+    Send {
+        target: String,
+        message: String,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        id: Option<MessageId>,
+        #[serde(default)]
+        reply_to: Option<MessageId>,
+        #[serde(default)]
+        format: MessageFormat,
+        /// Each node deletes the message this long after it receives it, by its own clock
+        #[serde(default)]
+        expires_in_seconds: Option<u64>,
+        #[serde(default)]
+        forwarded_from: Option<ForwardedFrom>,
+        /// Other nodes' messages are always stored as `Text`
+        #[serde(default)]
+        kind: MessageKind,
+        /// Counts up per counterparty so lost messages can be noticed
+        #[serde(default)]
+        seq: Option<u64>,
+        /// Chosen by the client so a retried POST doesn't store the message twice
+        #[serde(default)]
+        client_key: Option<String>,
+        /// Set instead of `message` once we share a key with the target
+        #[serde(default)]
+        sealed: Option<SealedContent>,
+        /// Structured data for bots, stored and passed on as is
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        metadata: HashMap<String, serde_json::Value>,
+        /// The author's signature over the plaintext, when they sign what they send
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<MessageSignature>,
+    },
+    /// Ask the counterparty to send its messages with these sequence numbers again
+    Resend {
+        from_seq: u64,
+        to_seq: u64,
+    },
+    /// Send a copy of a message from one chat to another target
+    Forward {
+        #[serde(alias = "from")]
+        from_chat: String,
+        message_id: MessageId,
+        #[serde(alias = "to")]
+        to_target: String,
+    },
+    /// The attachment bytes ride in the request payload. Without a mime,
+    /// the payload's own mime is used.
+    #[serde(alias = "SendFile")]
+    SendAttachment {
+        target: String,
+        filename: String,
+        #[serde(default)]
+        mime: String,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        id: Option<MessageId>,
+    },
+    Edit {
+        #[serde(alias = "counterparty")]
+        target: String,
+        #[serde(alias = "id")]
+        message_id: MessageId,
+        new_content: String,
+        /// Set instead of `new_content` once we share a key with the target
+        #[serde(default)]
+        sealed: Option<SealedContent>,
+    },
+    Delete {
+        #[serde(alias = "counterparty")]
+        target: String,
+        #[serde(default)]
+        message_id: Option<MessageId>,
+        /// Position in the chat, for clients that don't track message ids
+        #[serde(default)]
+        index: Option<usize>,
+    },
+    /// Without an id, everything in the chat so far has been read
+    MarkRead {
+        #[serde(alias = "counterparty", alias = "chat")]
+        target: String,
+        #[serde(default, alias = "up_to_id")]
+        up_to_message_id: Option<MessageId>,
+    },
+    /// Reacting again with the same emoji takes the reaction back
+    React {
+        #[serde(alias = "counterparty")]
+        target: String,
+        message_id: MessageId,
+        emoji: String,
+    },
+    Pin {
+        target: String,
+        message_id: MessageId,
+    },
+    Unpin {
+        target: String,
+        message_id: MessageId,
+    },
+    /// Stars are ours alone and never reach the counterparty
+    Star {
+        chat: String,
+        message_id: MessageId,
+    },
+    Unstar {
+        chat: String,
+        message_id: MessageId,
+    },
+    Search {
+        query: String,
+        #[serde(default)]
+        counterparty: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Wipe one chat, or the whole archive when no counterparty is given
+    Clear {
+        #[serde(default)]
+        counterparty: Option<String>,
+    },
+    /// Ephemeral, never stored in the archive
+    Typing {
+        #[serde(alias = "counterparty")]
+        target: String,
+        /// Clients that only signal when typing starts can leave this out
+        #[serde(default = "typing_default")]
+        is_typing: bool,
+    },
+    /// Fanned out to every member; stored under the room's key in the archive
+    SendToRoom {
+        room: String,
+        message: String,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        id: Option<MessageId>,
+    },
+    /// Join a room, learning its members from `via` if we aren't in it yet
+    JoinRoom {
+        room: String,
+        #[serde(default)]
+        via: Option<String>,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    /// Start an empty chat; a chat that already exists is left as it is
+    CreateChat {
+        target: String,
+    },
+    /// Drop a chat and everything kept about it, telling the counterparty if `notify`
+    DeleteChat {
+        target: String,
+        #[serde(default)]
+        notify: bool,
+    },
+    /// Stop taking new messages in a chat, keeping it readable
+    CloseChat {
+        target: String,
+    },
+    ReopenChat {
+        target: String,
+    },
+    /// Hide a chat from the main list until it gets a new message
+    ArchiveChat {
+        target: String,
+    },
+    UnarchiveChat {
+        target: String,
+    },
+    ListArchived,
+    /// A room that only invited members can join. Messages go through `SendToRoom` with
+    /// the group's id as the room.
+    CreateGroup {
+        name: String,
+        members: Vec<String>,
+    },
+    /// Sent to each member when a group is created, carrying the full membership
+    GroupInvite {
+        group: String,
+        name: String,
+        members: Vec<String>,
+        #[serde(default)]
+        roles: HashMap<String, GroupRole>,
+    },
+    /// Changes to a group. Only its admins may make them, and each is passed on to
+    /// every member so all copies of the group stay the same.
+    AddMember {
+        group: String,
+        member: String,
+    },
+    RemoveMember {
+        group: String,
+        member: String,
+    },
+    RenameGroup {
+        group: String,
+        name: String,
+    },
+    /// Make a member an admin
+    PromoteMember {
+        group: String,
+        member: String,
+    },
+    /// Make an admin a plain member again
+    DemoteMember {
+        group: String,
+        member: String,
+    },
+    /// Unsent text for a chat; kept on this node only. Empty content discards the draft.
+    SaveDraft {
+        chat: String,
+        content: String,
+    },
+    GetDrafts,
+    /// Agree on a new key with the target, to start encrypting or to rotate
+    ExchangeKeys {
+        target: String,
+    },
+    /// The other half of `ExchangeKeys`, carrying the initiator's public key
+    KeyExchange {
+        public_key: [u8; 32],
+        version: u32,
+    },
+    /// Send a message at a later time, given in seconds since the epoch
+    Schedule {
+        target: String,
+        message: String,
+        deliver_at: u64,
+    },
+    ListScheduled,
+    CancelScheduled {
+        id: String,
+    },
+    /// Many messages to one target, delivered in a single request
+    SendBatch {
+        target: String,
+        messages: Vec<String>,
+    },
+    /// The `Send`s of a batch, as forwarded to the counterparty
+    ReceiveBatch {
+        sends: Vec<ChatRequest>,
+    },
+    /// The latest message and unread count of every chat, for a chat list
+    Summaries,
+    /// Adjust limits at runtime; only fields that are given change
+    Configure {
+        #[serde(default)]
+        send_timeout_secs: Option<u64>,
+        #[serde(default)]
+        max_message_length: Option<usize>,
+        #[serde(default)]
+        max_edit_history: Option<usize>,
+        #[serde(default)]
+        max_attachment_size: Option<usize>,
+    },
+    /// Probe whether this app is reachable on the target node
+    Ping {
+        target: String,
+    },
+    /// Ask the target when it was last active. It answers only if it shares that with us.
+    LastSeen {
+        target: String,
+    },
+    /// Ask the target a question with fixed answers
+    CreatePoll {
+        target: String,
+        question: String,
+        options: Vec<String>,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        id: Option<MessageId>,
+    },
+    /// Share a point on the map, in degrees
+    SendLocation {
+        target: String,
+        lat: f64,
+        lon: f64,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        id: Option<MessageId>,
+    },
+    /// Voting again moves the vote to the new option
+    Vote {
+        chat: String,
+        poll_id: MessageId,
+        option_index: usize,
+    },
+    /// Stop accepting votes; only the poll's author may close it
+    ClosePoll {
+        chat: String,
+        poll_id: MessageId,
+    },
+    /// Words the content filter looks for, matched whole and ignoring case
+    AddFilterWord {
+        word: String,
+    },
+    RemoveFilterWord {
+        word: String,
+    },
+    /// What to do with messages containing a filtered word
+    SetFilterAction {
+        action: FilterAction,
+    },
+    /// Message and character counts per chat
+    Stats,
+    /// Set or clear how much history each chat keeps
+    SetRetention {
+        #[serde(default)]
+        retention: Option<Retention>,
+    },
+    /// Replace every setting at once
+    UpdateSettings {
+        settings: Settings,
+    },
+    /// Silently drop messages from `node` until it's unblocked
+    Block {
+        node: String,
+    },
+    Unblock {
+        node: String,
+    },
+    /// Stop notifying about `chat` for `duration_secs`, or until unmuted when unset
+    Mute {
+        chat: String,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    Unmute {
+        chat: String,
+    },
+    /// Save `node` as a contact, starting an empty chat with it. The nickname becomes its alias.
+    AddContact {
+        node: String,
+        #[serde(default)]
+        nickname: Option<String>,
+    },
+    /// Forget a contact and its nickname; the chat stays
+    RemoveContact {
+        node: String,
+    },
+    ListContacts,
+    /// Move the messages `node` sent before we knew them into a chat
+    AcceptChat {
+        node: String,
+    },
+    /// Drop `node`'s held messages and anything else it sends
+    RejectChat {
+        node: String,
+    },
+    ListMessageRequests,
+    /// Who we have chats with, without any messages
+    Conversations,
+    /// Send the same message to everyone we have a chat with; rooms and groups are left out
+    Broadcast {
+        message: String,
+    },
+    /// Replace how `chat` notifies us
+    SetChatSettings {
+        chat: String,
+        settings: ChatSettings,
+    },
+    /// Move the chat with `old` to `new`, for a counterparty whose node name changed
+    Rename {
+        old: String,
+        new: String,
+    },
+    /// Show `node` as `alias` in our UI; an empty alias goes back to the node name
+    SetAlias {
+        node: String,
+        alias: String,
+    },
+    History,
+    /// Sent over a WebSocket to only get new messages from `chat` on it, or from every
+    /// chat again when null
+    Subscribe {
+        chat: Option<String>,
+    },
+}
+
+fn typing_default() -> bool {
+    true
+}
+
+impl ChatRequest {
+    /// The text a request would store, so limits can be checked in one place
+    fn contents(&self) -> Vec<&str> {
+        match self {
+            ChatRequest::Send { message, .. }
+            | ChatRequest::SendToRoom { message, .. }
+            | ChatRequest::Broadcast { message } => vec![message],
+            ChatRequest::Edit { new_content, .. } => vec![new_content],
+            ChatRequest::Schedule { message, .. } => vec![message],
+            ChatRequest::SendLocation {
+                label: Some(label), ..
+            } => vec![label],
+            ChatRequest::CreatePoll {
+                question, options, ..
+            } => std::iter::once(question)
+                .chain(options)
+                .map(String::as_str)
+                .collect(),
+            ChatRequest::SendBatch { messages, .. } => {
+                messages.iter().map(String::as_str).collect()
+            }
+            ChatRequest::ReceiveBatch { sends } => {
+                sends.iter().flat_map(ChatRequest::contents).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The same text as `contents`, for the content filter to change
+    fn contents_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            ChatRequest::Send { message, .. }
+            | ChatRequest::SendToRoom { message, .. }
+            | ChatRequest::Broadcast { message } => vec![message],
+            ChatRequest::Edit { new_content, .. } => vec![new_content],
+            ChatRequest::Schedule { message, .. } => vec![message],
+            ChatRequest::SendLocation {
+                label: Some(label), ..
+            } => vec![label],
+            ChatRequest::CreatePoll {
+                question, options, ..
+            } => std::iter::once(question).chain(options).collect(),
+            ChatRequest::SendBatch { messages, .. } => messages.iter_mut().collect(),
+            ChatRequest::ReceiveBatch { sends } => sends
+                .iter_mut()
+                .flat_map(ChatRequest::contents_mut)
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Requests that only read what we have, which other processes on our node may make
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            ChatRequest::Stats
+                | ChatRequest::Search { .. }
+                | ChatRequest::Summaries
+                | ChatRequest::Conversations
+        )
+    }
+
+    /// The nodes a request would be sent on to
+    fn targets(&self) -> Vec<&str> {
+        match self {
+            ChatRequest::Send { target, .. }
+            | ChatRequest::SendAttachment { target, .. }
+            | ChatRequest::Edit { target, .. }
+            | ChatRequest::Delete { target, .. }
+            | ChatRequest::MarkRead { target, .. }
+            | ChatRequest::React { target, .. }
+            | ChatRequest::Pin { target, .. }
+            | ChatRequest::Unpin { target, .. }
+            | ChatRequest::Typing { target, .. }
+            | ChatRequest::Ping { target }
+            | ChatRequest::LastSeen { target }
+            | ChatRequest::Schedule { target, .. }
+            | ChatRequest::SendBatch { target, .. }
+            | ChatRequest::ExchangeKeys { target }
+            | ChatRequest::CreatePoll { target, .. }
+            | ChatRequest::SendLocation { target, .. }
+            | ChatRequest::CreateChat { target }
+            | ChatRequest::DeleteChat { target, .. }
+            | ChatRequest::CloseChat { target }
+            | ChatRequest::ReopenChat { target }
+            | ChatRequest::ArchiveChat { target }
+            | ChatRequest::UnarchiveChat { target } => vec![target],
+            ChatRequest::AddContact { node, .. }
+            | ChatRequest::RemoveContact { node }
+            | ChatRequest::AcceptChat { node }
+            | ChatRequest::RejectChat { node } => vec![node],
+            ChatRequest::Vote { chat, .. } | ChatRequest::ClosePoll { chat, .. } => vec![chat],
+            ChatRequest::Forward { to_target, .. } => vec![to_target],
+            ChatRequest::JoinRoom { via: Some(via), .. } => vec![via],
+            ChatRequest::CreateGroup { members, .. } | ChatRequest::GroupInvite { members, .. } => {
+                members.iter().map(String::as_str).collect()
+            }
+            ChatRequest::AddMember { member, .. }
+            | ChatRequest::RemoveMember { member, .. }
+            | ChatRequest::PromoteMember { member, .. }
+            | ChatRequest::DemoteMember { member, .. } => vec![member],
+            _ => vec![],
+        }
+    }
+}
+
+/// Why a request from another process can't be trusted to speak for its node, if it can't.
+/// Only this app, on our node or another, may send chat requests, besides read-only ones
+/// from other processes on our node. A node may only
+/// act in its own chat with us. Handlers take any other target to mean we're acting
+/// ourselves, in our chat with that node.
+pub(crate) fn impersonation(
+    our: &Address,
+    state: &State,
+    source: &Address,
+    chat_request: &ChatRequest,
+) -> Option<String> {
+    if source.process.to_string() != state.process {
+        if source.node == our.node && chat_request.is_read_only() {
+            return None;
+        }
+        return Some(format!("{} is not a chat process", source.process));
+    }
+    if source.node == our.node {
+        return None;
+    }
+    let elsewhere = match chat_request {
+        // Membership requests name the other members, not the chat they're for
+        ChatRequest::CreateGroup { .. }
+        | ChatRequest::GroupInvite { .. }
+        | ChatRequest::AddMember { .. }
+        | ChatRequest::RemoveMember { .. }
+        | ChatRequest::PromoteMember { .. }
+        | ChatRequest::DemoteMember { .. }
+        | ChatRequest::JoinRoom { .. } => None,
+        _ => chat_request
+            .targets()
+            .into_iter()
+            .find(|target| *target != our.node),
+    };
+    elsewhere.map(|target| {
+        format!(
+            "{} sent a request as if it were in a chat with {}",
+            source.node, target
+        )
+    })
+}
+
+/// Run every piece of text in a request through the content filter. Returns what the
+/// filter did if anything matched, or the error to answer with if it rejects the request.
+fn apply_filter(
+    filter: &ContentFilter,
+    chat_request: &mut ChatRequest,
+) -> Result<Option<FilterAction>, (StatusCode, String)> {
+    let mut filtered = None;
+    for content in chat_request.contents_mut() {
+        let Some(censored) = filter.censor(content) else {
+            continue;
+        };
+        match filter.action {
+            FilterAction::Reject => {
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "message rejected by the content filter".to_string(),
+                ));
+            }
+            FilterAction::Censor => *content = censored,
+            FilterAction::Flag => {}
+        }
+        filtered = Some(filter.action);
+    }
+    Ok(filtered)
+}
+
+/// Whether `name` could be a node identity like `alice.uq`
+pub(crate) fn is_valid_node_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// Like requests, each response is built once and sent straight off
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ChatResponse {
+    Ack,
+    /// Answers another node's `Send` with the id the message was stored under, so the
+    /// sender can tell which of its messages in flight got through
+    Received {
+        request_id: MessageId,
+    },
+    History {
+        messages: MessageArchive,
+        last_read: HashMap<String, ReadMarker>,
+        pinned: HashMap<String, Vec<MessageId>>,
+        rooms: HashMap<String, Vec<String>>,
+        /// Names of the rooms that are groups, by id
+        groups: HashMap<String, String>,
+        /// Owners and admins of each group, by id
+        group_roles: HashMap<String, HashMap<String, GroupRole>>,
+        drafts: HashMap<String, String>,
+        unread: HashMap<String, u32>,
+        /// Chats that are read-only
+        closed: HashSet<String>,
+        /// Chats hidden from the main list; left out of `messages` unless asked for
+        archived: HashSet<String>,
+        /// Our display names for other nodes
+        aliases: HashMap<String, String>,
+        /// When each muted chat unmutes; never when null
+        muted: HashMap<String, Option<u64>>,
+    },
+    Group {
+        group: String,
+        name: String,
+        members: Vec<String>,
+        /// Owners and admins; everyone else is a plain member
+        roles: HashMap<String, GroupRole>,
+    },
+    Chat(ChatSummary),
+    Drafts {
+        drafts: HashMap<String, String>,
+    },
+    Pong,
+    LastSeen {
+        last_seen: LastSeen,
+    },
+    Blocked {
+        nodes: Vec<String>,
+    },
+    Archived {
+        chats: Vec<String>,
+    },
+    /// Sorted alphabetically
+    Conversations {
+        chats: Vec<String>,
+    },
+    /// Each chat a broadcast went to, with why it failed for those it didn't
+    BroadcastResult {
+        sent: Vec<String>,
+        failed: HashMap<String, String>,
+    },
+    /// Sorted by node name
+    Contacts {
+        contacts: Vec<Contact>,
+    },
+    /// Held messages, by the node that sent them
+    MessageRequests {
+        requests: HashMap<String, Vec<ChatMessage>>,
+    },
+    Filter {
+        words: Vec<String>,
+        action: FilterAction,
+    },
+    Settings(Settings),
+    Stats {
+        stats: HashMap<String, ChatStats>,
+    },
+    /// Unread counts without the messages, for polling badges
+    Unread {
+        total: u32,
+        chats: HashMap<String, u32>,
+    },
+    /// How many messages from an imported archive were new to us
+    Imported {
+        added: usize,
+    },
+    /// Earlier versions of a message, oldest first
+    EditHistory {
+        message_id: MessageId,
+        content: String,
+        revisions: Vec<Revision>,
+    },
+    KeyExchange {
+        public_key: [u8; 32],
+    },
+    /// Most recently active chats first
+    Summaries {
+        items: Vec<ChatSummary>,
+        aliases: HashMap<String, String>,
+    },
+    /// For monitoring and the UI's connection indicator
+    Status {
+        node: String,
+        conversations: usize,
+        messages: usize,
+        websocket_channels: usize,
+        uptime_secs: u64,
+    },
+    Presence {
+        presence: HashMap<String, bool>,
+        last_seen: HashMap<String, u64>,
+    },
+    /// The members of a room we were asked to let a node into
+    Members {
+        room: String,
+        members: Vec<String>,
+    },
+    Pinned {
+        messages: MessageArchive,
+    },
+    Mentions {
+        messages: MessageArchive,
+    },
+    /// Starred messages from every chat, oldest first
+    Starred {
+        messages: Vec<NewMessage>,
+    },
+    Page {
+        chat: String,
+        messages: Vec<ChatMessage>,
+        offset: usize,
+        total: usize,
+        last_read: ReadMarker,
+        /// Pass as `before` to get the page of older messages; null at the start of the chat
+        next_cursor: Option<MessageId>,
+    },
+    /// The message as stored, so the UI can swap it in for its placeholder
+    Sent(Box<ChatMessage>),
+    /// A send whose client key we've seen before; nothing new was stored
+    AlreadySent {
+        id: MessageId,
+    },
+    Scheduled {
+        id: String,
+    },
+    /// Scheduled messages that haven't gone out yet, soonest first
+    ScheduledMessages {
+        scheduled: Vec<ScheduledMessage>,
+    },
+    /// How each message of a batch fared, in order
+    BatchResult {
+        results: Vec<BatchItemResult>,
+    },
+    SearchResults {
+        hits: Vec<SearchHit>,
+    },
+    Error {
+        code: u16,
+        message: String,
+    },
+}
+
+impl ChatResponse {
+    pub(crate) fn error(code: StatusCode, message: impl Into<String>) -> Self {
+        ChatResponse::Error {
+            code: code.as_u16(),
+            message: message.into(),
+        }
+    }
+
+    /// The HTTP status to answer with when this is sent back over HTTP
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ChatResponse::Error { code, .. } => {
+                StatusCode::from_u16(*code).unwrap_or(StatusCode::BAD_REQUEST)
+            }
+            ChatResponse::Sent(_) | ChatResponse::Scheduled { .. } => StatusCode::CREATED,
+            _ => StatusCode::OK,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub(crate) struct ChatMessage {
+    #[serde(default)]
+    pub(crate) id: MessageId,
+    pub(crate) author: String,
+    pub(crate) content: String,
+    #[serde(default)]
+    pub(crate) timestamp: u64,
+    #[serde(default)]
+    pub(crate) edited_at: Option<u64>,
+    #[serde(default)]
+    pub(crate) edited: bool,
+    /// What the content was before each edit, oldest first. Left out of `History` to keep
+    /// it small; served from `/messages/history`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) edit_history: Vec<Revision>,
+    #[serde(default)]
+    pub(crate) deleted: bool,
+    /// Emoji mapped to the nodes that reacted with it
+    #[serde(default)]
+    pub(crate) reactions: HashMap<String, Vec<String>>,
+    /// The message this one is a reply to
+    #[serde(default)]
+    pub(crate) reply_to: Option<MessageId>,
+    #[serde(default)]
+    pub(crate) format: MessageFormat,
+    #[serde(default)]
+    pub(crate) attachment: Option<AttachmentMeta>,
+    #[serde(default)]
+    pub(crate) kind: MessageKind,
+    /// Nodes named with `@node` in the content
+    #[serde(default)]
+    pub(crate) mentions: Vec<String>,
+    /// Whether our own message has reached the counterparty
+    #[serde(default)]
+    pub(crate) status: DeliveryStatus,
+    /// When a disappearing message gets purged, by our clock
+    #[serde(default)]
+    pub(crate) expires_at: Option<u64>,
+    /// Where a forwarded message originally came from
+    #[serde(default)]
+    pub(crate) forwarded_from: Option<ForwardedFrom>,
+    #[serde(default)]
+    pub(crate) starred: bool,
+    /// The sender's sequence number in this chat, for sorting and gap detection
+    #[serde(default)]
+    pub(crate) seq: Option<u64>,
+    /// Whether the content crossed the network encrypted; chats without a key fall back
+    /// to plaintext and leave this unset
+    #[serde(default)]
+    pub(crate) encrypted: bool,
+    /// Structured data attached by the sender, like a ticket id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) metadata: HashMap<String, serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) poll: Option<Poll>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) location: Option<Location>,
+    /// What the content filter did to this message, if it matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) filtered: Option<FilterAction>,
+    /// Whether the author's signature checked out; our own messages leave this unset
+    #[serde(default)]
+    pub(crate) verified: bool,
+    /// The content before it was escaped, only kept with `trusted_content` on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) raw_content: Option<String>,
+    /// An excerpt of the `reply_to` message, filled in on the copies we send to the UI
+    /// and never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) quoted: Option<Quote>,
+}
+
+/// What a reply shows of the message it quotes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Quote {
+    Available {
+        author: String,
+        excerpt: String,
+    },
+    /// Deleted, expired, or never known to us
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Location {
+    lat: f64,
+    lon: f64,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Poll {
+    options: Vec<PollOption>,
+    #[serde(default)]
+    closed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PollOption {
+    text: String,
+    votes: usize,
+    voters: Vec<String>,
+}
+
+impl Poll {
+    /// Record `voter`'s choice, replacing any earlier vote of theirs
+    fn vote(&mut self, voter: &str, option_index: usize) {
+        for (index, option) in self.options.iter_mut().enumerate() {
+            option.voters.retain(|node| node != voter);
+            if index == option_index {
+                option.voters.push(voter.to_string());
+            }
+            option.votes = option.voters.len();
+        }
+    }
+}
+
+/// The content a message had until it was edited at `timestamp`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Revision {
+    timestamp: u64,
+    content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ForwardedFrom {
+    author: String,
+    chat: String,
+}
+
+impl ForwardedFrom {
+    /// The same credit with `f`, such as `sanitize_content`, applied to both names
+    fn map(&self, f: impl Fn(&str) -> String) -> ForwardedFrom {
+        ForwardedFrom {
+            author: f(&self.author),
+            chat: f(&self.chat),
+        }
+    }
+}
+
+/// Describes a file attached to a message. The bytes are kept in the VFS and served from
+/// `GET /attachment/<id>` or `GET /messages/attachment?chat=<chat>&id=<id>`, adding
+/// `thumbnail=true` to the query for the thumbnail.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AttachmentMeta {
+    pub(crate) filename: String,
+    pub(crate) mime: String,
+    pub(crate) size: u64,
+    /// Whether a downscaled preview exists, which only images that decode get
+    #[serde(default)]
+    pub(crate) thumbnail: bool,
+}
+
+/// Messages we receive, and those stored before tracking, count as delivered
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum DeliveryStatus {
+    #[serde(alias = "Sending")]
+    Pending,
+    #[default]
+    Delivered,
+    Failed,
+    /// The counterparty's read receipt covers this message
+    Read,
+}
+
+/// Rides along with a forwarded message so its response can be matched back to it
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DeliveryContext {
+    pub(crate) chat: String,
+    pub(crate) message_id: MessageId,
+    /// Failed attempts before this one
+    #[serde(default)]
+    pub(crate) attempts: u32,
+}
+
+/// When a node was last active, as far as it's willing to say
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum LastSeen {
+    At(u64),
+    /// The node doesn't share this with us
+    Hidden,
+}
+
+/// Rides along with a presence ping so its answer, or its failure, can be matched to the node
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PingContext {
+    pub(crate) ping: String,
+}
+
+/// One of our messages waiting for another delivery attempt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PendingMessage {
+    pub(crate) chat: String,
+    pub(crate) message_id: MessageId,
+    pub(crate) attempts: u32,
+    pub(crate) next_retry: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum MessageKind {
+    #[default]
+    Text,
+    Image,
+    File,
+    /// Generated by this process, such as delivery failures
+    System,
+    /// Styled apart from ordinary text, like an announcement
+    Notice,
+    /// The content is the question; the options and votes are in `poll`
+    Poll,
+    /// A voice note, played from its attachment
+    Audio,
+    /// A map pin, described by `location`
+    Location,
+}
+
+/// How the UI should render a message's content
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MessageFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+/// Message content encrypted with a chat key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SealedContent {
+    key_version: u32,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// An Ed25519 signature over `signed_bytes`, with the key that made it. The first key we
+/// see from a node is the one we hold it to afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct MessageSignature {
+    public_key: [u8; 32],
+    signature: Vec<u8>,
+}
+
+/// A message waiting in the schedule, kept apart from the archive until it's sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ScheduledMessage {
+    pub(crate) id: String,
+    pub(crate) target: String,
+    pub(crate) message: String,
+    pub(crate) deliver_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BatchItemResult {
+    id: MessageId,
+    status: DeliveryStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChatSummary {
+    counterparty: String,
+    last_message: Option<ChatMessage>,
+    /// The start of `last_message`, for a sidebar line
+    preview: String,
+    /// When the newest message was sent, deleted or not
+    last_activity: Option<u64>,
+    /// Messages in the chat, not counting deleted ones
+    messages: usize,
+    pub(crate) unread: u32,
+    muted: bool,
+    /// Whether the counterparty answered last time, if we've tried
+    online: Option<bool>,
+    last_seen: Option<u64>,
+    /// What the counterparty told us of when it was last active, if we've asked
+    last_active: Option<LastSeen>,
+    settings: ChatSettings,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Contact {
+    node: String,
+    nickname: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct NewMessage {
+    chat: String,
+    chat_kind: ChatKind,
+    /// The UI shows muted messages without notifying
+    #[serde(default)]
+    muted: bool,
+    /// Whether to notify of this message, from the chat's settings, mute and mentions
+    #[serde(default)]
+    should_notify: bool,
+    /// Our nickname for the author, if we've given them one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(flatten)]
+    message: ChatMessage,
+}
+
+/// What a member may do in a group. Each role may do all that the ones before it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub(crate) enum GroupRole {
+    Member,
+    /// May add, remove, promote and demote members below them, and rename the group
+    Admin,
+    /// Whoever created the group; can't be removed or demoted
+    Owner,
+}
+
+/// `node`'s role in `group`, or `None` if it isn't a member
+fn group_role(state: &State, group: &str, node: &str) -> Option<GroupRole> {
+    if !state.rooms.get(group)?.iter().any(|member| member == node) {
+        return None;
+    }
+    Some(
+        state
+            .group_roles
+            .get(group)
+            .and_then(|roles| roles.get(node))
+            .copied()
+            .unwrap_or(GroupRole::Member),
+    )
+}
+
+/// The group as we know it, for the UI
+fn group_info(state: &State, group: &str) -> ChatResponse {
+    ChatResponse::Group {
+        group: group.to_string(),
+        name: state.groups.get(group).cloned().unwrap_or_default(),
+        members: state.rooms.get(group).cloned().unwrap_or_default(),
+        roles: state.group_roles.get(group).cloned().unwrap_or_default(),
+    }
+}
+
+/// Everyone but us who was or now is in a group, once each, and whether they were in it
+fn group_change_recipients<'a>(
+    our: &str,
+    before: &'a [String],
+    after: &'a [String],
+) -> Vec<(&'a String, bool)> {
+    let mut seen = HashSet::new();
+    after
+        .iter()
+        .chain(before)
+        .filter(|member| member.as_str() != our && seen.insert(member.as_str()))
+        .map(|member| (member, before.contains(member)))
+        .collect()
+}
+
+/// Check that `by` may make this change to its group and make it, or say why not
+fn change_group(
+    state: &mut State,
+    by: &str,
+    chat_request: &ChatRequest,
+) -> Result<(), (StatusCode, String)> {
+    let (group, member) = match chat_request {
+        ChatRequest::AddMember { group, member }
+        | ChatRequest::RemoveMember { group, member }
+        | ChatRequest::PromoteMember { group, member }
+        | ChatRequest::DemoteMember { group, member } => (group, Some(member)),
+        ChatRequest::RenameGroup { group, .. } => (group, None),
+        _ => return Ok(()),
+    };
+    if !state.groups.contains_key(group) {
+        return Err((StatusCode::NOT_FOUND, format!("no group {}", group)));
+    }
+    let role = group_role(state, group, by).unwrap_or(GroupRole::Member);
+    if role < GroupRole::Admin {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("only admins can change {}", group),
+        ));
+    }
+    let member_role = member.and_then(|member| group_role(state, group, member));
+    match (chat_request, member_role) {
+        (ChatRequest::AddMember { member, .. }, Some(_)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("{} is already in {}", member, group),
+            ));
+        }
+        (ChatRequest::AddMember { .. } | ChatRequest::RenameGroup { .. }, _) => {}
+        (_, None) => {
+            return Err((StatusCode::NOT_FOUND, format!("not a member of {}", group)));
+        }
+        // Admins can only act on those below them
+        (_, Some(member_role)) if member_role >= role => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "can't change a member whose role isn't below yours".to_string(),
+            ));
+        }
+        _ => {}
+    }
+
+    let roles = state.group_roles.entry(group.clone()).or_default();
+    let members = state.rooms.entry(group.clone()).or_default();
+    match chat_request {
+        ChatRequest::AddMember { member, .. } => members.push(member.clone()),
+        ChatRequest::RemoveMember { member, .. } => {
+            members.retain(|node| node != member);
+            roles.remove(member);
+        }
+        ChatRequest::PromoteMember { member, .. } => {
+            roles.insert(member.clone(), GroupRole::Admin);
+        }
+        ChatRequest::DemoteMember { member, .. } => {
+            roles.remove(member);
+        }
+        ChatRequest::RenameGroup { name, .. } => {
+            state.groups.insert(group.clone(), sanitize_content(name));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Whether a chat is with one node, or a room or group keyed `#<id>` in the archive
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum ChatKind {
+    Direct,
+    Group,
+}
+
+impl ChatKind {
+    fn of(chat: &str) -> Self {
+        match chat.starts_with('#') {
+            true => ChatKind::Group,
+            false => ChatKind::Direct,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SearchHit {
+    counterparty: String,
+    message: ChatMessage,
+    index: usize,
+    snippet: String,
+}
+
+/// Events pushed to the UI over the WebSocket
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ChatEvent {
+    NewMessage(Box<NewMessage>),
+    MessageEdited {
+        chat: String,
+        message_id: MessageId,
+        content: String,
+    },
+    MessageDeleted {
+        chat: String,
+        message_id: MessageId,
+    },
+    MessageExpired {
+        chat: String,
+        message_id: MessageId,
+    },
+    ChatClosed {
+        chat: String,
+        closed: bool,
+    },
+    /// Keeps unread badges current; a count of 0 means the chat was read
+    UnreadChanged {
+        chat: String,
+        count: u32,
+    },
+    /// `node` started or stopped answering us
+    PresenceChanged {
+        node: String,
+        online: bool,
+    },
+    /// A new message brought an archived chat back to the main list
+    ChatUnarchived {
+        chat: String,
+    },
+    /// A node we don't know yet sent a message, which waits for `AcceptChat`
+    MessageRequest {
+        node: String,
+        message: Box<ChatMessage>,
+    },
+    /// `by` deleted the chat; the UI drops it when that's us
+    ChatDeleted {
+        chat: String,
+        by: String,
+    },
+    DeliveryUpdate {
+        chat: String,
+        message_id: MessageId,
+        status: DeliveryStatus,
+    },
+    PollUpdated {
+        chat: String,
+        poll_id: MessageId,
+        poll: Poll,
+    },
+    ReactionAdded {
+        chat: String,
+        message_id: MessageId,
+        reactions: HashMap<String, Vec<String>>,
+    },
+    ReadReceipt {
+        chat: String,
+        up_to_message_id: MessageId,
+    },
+    MessagePinned {
+        chat: String,
+        message_id: MessageId,
+        pinned: bool,
+    },
+    /// Keeps other tabs of our own UI in sync
+    MessageStarred {
+        chat: String,
+        message_id: MessageId,
+        starred: bool,
+    },
+    /// Sent alongside `NewMessage` when an incoming message mentions us
+    Mention {
+        chat: String,
+        message_id: MessageId,
+        author: String,
+    },
+    /// One batch of a chat's history, streamed when a WebSocket opens. `seq` counts up from 0.
+    HistoryChunk {
+        seq: u64,
+        chat: String,
+        messages: Vec<ChatMessage>,
+    },
+    /// Follows the last `HistoryChunk`; `chunks` lets the UI check nothing went missing
+    HistoryDone {
+        chunks: u64,
+    },
+    /// The UI should treat this as stale if no follow-up arrives within a few seconds
+    Typing {
+        chat: String,
+        author: String,
+        is_typing: bool,
+        timestamp: u64,
+    },
+}
+
+/// Largest metadata map we store on a message, in bytes of JSON
+const MAX_METADATA_SIZE: usize = 4 * 1024;
+
+/// Longest name a forwarded message may credit, in bytes
+const MAX_FORWARDED_NAME_LENGTH: usize = 256;
+
+/// Longest reaction, in bytes; enough for emoji joined from several code points
+const MAX_EMOJI_LENGTH: usize = 64;
+
+/// Metadata with `f`, such as `sanitize_content`, applied to every key and string in it
+fn map_metadata(
+    metadata: &HashMap<String, serde_json::Value>,
+    f: &impl Fn(&str) -> String,
+) -> HashMap<String, serde_json::Value> {
+    fn map_value(value: &serde_json::Value, f: &impl Fn(&str) -> String) -> serde_json::Value {
+        match value {
+            serde_json::Value::String(s) => serde_json::Value::String(f(s)),
+            serde_json::Value::Array(values) => {
+                values.iter().map(|value| map_value(value, f)).collect()
+            }
+            serde_json::Value::Object(values) => values
+                .iter()
+                .map(|(key, value)| (f(key), map_value(value, f)))
+                .collect(),
+            value => value.clone(),
+        }
+    }
+    metadata
+        .iter()
+        .map(|(key, value)| (f(key), map_value(value, f)))
+        .collect()
+}
+
+pub(crate) type MessageId = String;
+
+/// Archive key for a room's messages; node names can't start with '#', so it can't collide
+fn room_key(room: &str) -> String {
+    format!("#{}", room)
+}
+
+/// Escape HTML in message content so it can't inject markup into the UI.
+/// Works in a single pass, so deeply nested or very long input is fine.
+fn sanitize_content(content: &str) -> String {
+    let mut sanitized = String::with_capacity(content.len());
+    for c in content.chars() {
+        match c {
+            '&' => sanitized.push_str("&amp;"),
+            '<' => sanitized.push_str("&lt;"),
+            '>' => sanitized.push_str("&gt;"),
+            '"' => sanitized.push_str("&quot;"),
+            '\'' => sanitized.push_str("&#39;"),
+            c => sanitized.push(c),
+        }
+    }
+    sanitized
+}
+
+/// Undo `sanitize_content`, for stored content that goes back out to another node
+pub(crate) fn unsanitize(content: &str) -> String {
+    content
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Current unix time in seconds
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Longest side of image thumbnails, in pixels
+const THUMBNAIL_SIZE: u32 = 256;
+
+pub(crate) fn thumbnail_path(our: &Address, message_id: &str) -> anyhow::Result<String> {
+    Ok(format!("{}.thumb", attachment_path(our, message_id)?))
+}
+
+/// Downscale an image to a PNG thumbnail, or `None` if the bytes don't decode
+fn make_thumbnail(bytes: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(bytes).ok()?;
+    let mut thumbnail = Vec::new();
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(
+            &mut std::io::Cursor::new(&mut thumbnail),
+            image::ImageOutputFormat::Png,
+        )
+        .ok()?;
+    Some(thumbnail)
+}
+
+pub(crate) fn attachment_path(our: &Address, message_id: &str) -> anyhow::Result<String> {
+    let drive = create_drive(our.package_id(), "chat")?;
+    // Ids contain the author's node name, so keep them to path-safe characters
+    let name: String = message_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Ok(format!("{}/attachments/{}", drive, name))
+}
+
+/// The request the system timer process understands; it responds once the time is up
+#[derive(Serialize)]
+enum TimerAction {
+    SetTimer(u64),
+}
+
+/// Have the timer wake us in `seconds`, to purge expired messages or retry deliveries
+pub(crate) fn set_timer(our: &Address, seconds: u64) -> anyhow::Result<()> {
+    Request::new()
+        .target(Address {
+            node: our.node.clone(),
+            process: ProcessId::from_str("timer:sys:uqbar")?,
+        })
+        .ipc(serde_json::to_vec(&TimerAction::SetTimer(seconds * 1000))?)
+        .expects_response(seconds + 30)
+        .send()
+}
+
+/// Remove disappearing messages whose time is up and tell the UI which ones went
+pub(crate) fn purge_expired(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    let now = now();
+    let mut expired = vec![];
+    for (chat, messages) in state.message_archive.iter_mut() {
+        messages.retain(|message| match message.expires_at {
+            Some(expires_at) if expires_at <= now => {
+                expired.push((chat.clone(), message.id.clone()));
+                false
+            }
+            _ => true,
+        });
+    }
+    if expired.is_empty() {
+        return Ok(());
+    }
+    for (chat, _) in &expired {
+        state.recount_stats(chat);
+    }
+    save_state(our, state)?;
+
+    for (chat, message_id) in expired {
+        push_to_channels(our, state, &ChatEvent::MessageExpired { chat, message_id })?;
+    }
+    Ok(())
+}
+
+/// The address of this app on another node, which runs under the same process id as us
+fn chat_address(state: &State, node: &str) -> anyhow::Result<Address> {
+    Ok(Address {
+        node: node.to_string(),
+        process: ProcessId::from_str(&state.process)?,
+    })
+}
+
+/// Forward a chat request to this app on the target node and wait for its response
+fn forward_chat_request(
+    state: &State,
+    target: &str,
+    chat_request: &ChatRequest,
+) -> anyhow::Result<ChatResponse> {
+    forward_chat_request_with_payload(state, target, chat_request, None)
+}
+
+/// Like `forward_chat_request`, with bytes such as an attachment riding along in the payload.
+/// If the target can't be reached in time, the failure comes back as a `ChatResponse::Error`
+/// so the caller can offer a retry instead of the process panicking.
+pub(crate) fn forward_chat_request_with_payload(
+    state: &State,
+    target: &str,
+    chat_request: &ChatRequest,
+    payload: Option<Payload>,
+) -> anyhow::Result<ChatResponse> {
+    let mut request = Request::new()
+        .target(chat_address(state, target)?)
+        .ipc(serde_json::to_vec(chat_request)?);
+    if let Some(payload) = payload {
+        request = request.payload(payload);
+    }
+
+    match request.send_and_await_response(state.send_timeout())? {
+        Ok(response) => Ok(serde_json::from_slice(response.ipc())?),
+        Err(send_error) => {
+            print_to_terminal(
+                0,
+                &format!("chat: delivery to {} failed: {:?}", target, send_error.kind),
+            );
+            Ok(ChatResponse::error(
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("could not deliver to {}", target),
+            ))
+        }
+    }
+}
+
+/// Forward a message without blocking; its response or send error comes back to
+/// `handle_message` carrying a `DeliveryContext`
+fn send_tracked_chat_request(
+    state: &State,
+    target: &str,
+    chat_request: &ChatRequest,
+    context: &DeliveryContext,
+) -> anyhow::Result<()> {
+    let chat_request = seal_send(state, chat_request.clone())?;
+    Request::new()
+        .target(chat_address(state, target)?)
+        .ipc(serde_json::to_vec(&chat_request)?)
+        .expects_response(state.send_timeout())
+        .context(serde_json::to_vec(context)?)
+        .send()
+}
+
+/// Which of our messages the counterparty's response to a forward is about, and how it went
+pub(crate) fn delivery_outcome(
+    context: DeliveryContext,
+    ipc: &[u8],
+) -> (DeliveryContext, DeliveryStatus) {
+    match serde_json::from_slice::<ChatResponse>(ipc) {
+        // The id they stored is the message that got through
+        Ok(ChatResponse::Received { request_id }) => (
+            DeliveryContext {
+                message_id: request_id,
+                ..context
+            },
+            DeliveryStatus::Delivered,
+        ),
+        Ok(ChatResponse::Error { .. }) | Err(_) => (context, DeliveryStatus::Failed),
+        Ok(_) => (context, DeliveryStatus::Delivered),
+    }
+}
+
+/// Set the delivery status of one of our messages, unless it's gone or already read.
+/// Whether it was set.
+fn set_delivery_status(
+    state: &mut State,
+    chat: &str,
+    message_id: &str,
+    status: DeliveryStatus,
+) -> bool {
+    // The message may have been deleted or expired while in flight
+    let Some(message) = find_message_mut(&mut state.message_archive, chat, message_id) else {
+        return false;
+    };
+    // A read receipt can overtake the response to the send itself
+    if message.status == DeliveryStatus::Read {
+        return false;
+    }
+    message.status = status;
+    true
+}
+
+/// Record how delivery of one of our messages went and tell the UI
+pub(crate) fn update_delivery_status(
+    our: &Address,
+    state: &mut State,
+    context: DeliveryContext,
+    status: DeliveryStatus,
+) -> anyhow::Result<()> {
+    let DeliveryContext {
+        chat, message_id, ..
+    } = context;
+    if !set_delivery_status(state, &chat, &message_id, status) {
+        return Ok(());
+    }
+    save_state(our, state)?;
+
+    push_to_channels(
+        our,
+        state,
+        &ChatEvent::DeliveryUpdate {
+            chat: chat.clone(),
+            message_id: message_id.clone(),
+            status,
+        },
+    )?;
+
+    if status == DeliveryStatus::Failed {
+        let notice = ChatMessage {
+            id: state.new_message_id(&our.node),
+            author: our.node.clone(),
+            content: format!("Message {} could not be delivered", message_id),
+            timestamp: now(),
+            kind: MessageKind::System,
+            ..ChatMessage::default()
+        };
+        store_message(our, state, &chat, notice, false)?;
+    }
+    Ok(())
+}
+
+/// Turn a Diffie-Hellman shared secret into a chat key
+fn derive_key(shared: &SharedSecret) -> [u8; 32] {
+    Sha256::digest(shared.as_bytes()).into()
+}
+
+fn seal(key: &[u8; 32], key_version: u32, plaintext: &str) -> anyhow::Result<SealedContent> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = Aes256Gcm::new(key.into())
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| anyhow::anyhow!("failed to encrypt message"))?;
+
+    Ok(SealedContent {
+        key_version,
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })
+}
+
+/// Decrypt sealed content, or `None` if we don't have its key or it was tampered with
+fn open_sealed(keys: &ChatKeys, sealed: &SealedContent) -> Option<String> {
+    let key = keys.keys.get(&sealed.key_version)?;
+    if sealed.nonce.len() != 12 {
+        return None;
+    }
+    let plaintext = Aes256Gcm::new(key.into())
+        .decrypt(
+            Nonce::from_slice(&sealed.nonce),
+            sealed.ciphertext.as_slice(),
+        )
+        .ok()?;
+    String::from_utf8(plaintext).ok()
+}
+
+/// What a message signature covers: who wrote it, when, and what it says
+fn signed_bytes(author: &str, content: &str, timestamp: u64) -> Vec<u8> {
+    format!("{}\n{}\n{}", author, timestamp, content).into_bytes()
+}
+
+/// Check a signature on a message from `author`, holding them to the key they first signed with
+fn verify_signature(
+    state: &mut State,
+    author: &str,
+    content: &str,
+    timestamp: Option<u64>,
+    signature: &MessageSignature,
+) -> Result<(), String> {
+    if let Some(known) = state.verifying_keys.get(author) {
+        if known != &signature.public_key {
+            return Err(format!(
+                "{} signed with a different key than before",
+                author
+            ));
+        }
+    }
+    let timestamp = timestamp.ok_or("signed messages must carry a timestamp")?;
+    let key = VerifyingKey::from_bytes(&signature.public_key).map_err(|e| e.to_string())?;
+    let bytes = Signature::from_slice(&signature.signature).map_err(|e| e.to_string())?;
+    key.verify(&signed_bytes(author, content, timestamp), &bytes)
+        .map_err(|_| format!("signature does not match {}", author))?;
+
+    state
+        .verifying_keys
+        .entry(author.to_string())
+        .or_insert(signature.public_key);
+    Ok(())
+}
+
+/// Sign the content of a `Send` if signing is on, then encrypt it, or an edit's new
+/// content, if we share a key with its target
+fn seal_send(state: &State, mut chat_request: ChatRequest) -> anyhow::Result<ChatRequest> {
+    if let ChatRequest::Send {
+        ref message,
+        timestamp: Some(timestamp),
+        ref mut signature,
+        ..
+    } = chat_request
+    {
+        if let (true, Some(key)) = (state.settings.sign_messages, state.signing_key) {
+            let key = SigningKey::from_bytes(&key);
+            *signature = Some(MessageSignature {
+                public_key: key.verifying_key().to_bytes(),
+                signature: key
+                    .sign(&signed_bytes(&state.node, message, timestamp))
+                    .to_vec(),
+            });
+        }
+    }
+    if let ChatRequest::Send {
+        ref target,
+        ref mut message,
+        ref mut sealed,
+        ..
+    }
+    | ChatRequest::Edit {
+        ref target,
+        new_content: ref mut message,
+        ref mut sealed,
+        ..
+    } = chat_request
+    {
+        if let Some(keys) = state.chat_keys.get(target) {
+            if let Some(key) = keys.keys.get(&keys.current) {
+                *sealed = Some(seal(key, keys.current, message)?);
+                message.clear();
+            }
+        }
+    }
+    Ok(chat_request)
+}
+
+/// Delivery attempts before one of our messages is marked failed
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, in seconds; doubled after every failed attempt
+const RETRY_BACKOFF_SECS: u64 = 10;
+
+/// How long to wait before trying again after `attempts` failed deliveries, or `None`
+/// once it's time to give up
+fn retry_backoff(attempts: u32) -> Option<u64> {
+    (attempts < MAX_DELIVERY_ATTEMPTS).then(|| RETRY_BACKOFF_SECS << (attempts - 1))
+}
+
+/// Queue a message whose delivery failed for another attempt, or give up on it
+pub(crate) fn schedule_retry(
+    our: &Address,
+    state: &mut State,
+    context: DeliveryContext,
+) -> anyhow::Result<()> {
+    match queue_retry(state, context) {
+        Ok(backoff) => {
+            save_state(our, state)?;
+            set_timer(our, backoff)
+        }
+        Err(context) => update_delivery_status(our, state, context, DeliveryStatus::Failed),
+    }
+}
+
+/// Queue a message whose delivery failed, returning how long until it's tried again,
+/// or hand back its context once it's out of attempts
+fn queue_retry(state: &mut State, context: DeliveryContext) -> Result<u64, DeliveryContext> {
+    let attempts = context.attempts + 1;
+    let Some(backoff) = retry_backoff(attempts) else {
+        return Err(context);
+    };
+    state.pending.push(PendingMessage {
+        chat: context.chat,
+        message_id: context.message_id,
+        attempts,
+        next_retry: now() + backoff,
+    });
+    Ok(backoff)
+}
+
+/// A `Send` that delivers one of our stored messages to `chat` once more
+fn resend_request(chat: &str, message: &ChatMessage) -> ChatRequest {
+    ChatRequest::Send {
+        target: chat.to_string(),
+        message: unsanitize(&message.content),
+        timestamp: Some(message.timestamp),
+        id: Some(message.id.clone()),
+        reply_to: message.reply_to.clone(),
+        format: message.format,
+        expires_in_seconds: message
+            .expires_at
+            .map(|expires_at| expires_at.saturating_sub(now())),
+        forwarded_from: message
+            .forwarded_from
+            .as_ref()
+            .map(|forwarded_from| forwarded_from.map(unsanitize)),
+        kind: message.kind,
+        seq: message.seq,
+        client_key: None,
+        sealed: None,
+        metadata: map_metadata(&message.metadata, &unsanitize),
+        signature: None,
+    }
+}
+
+/// Send again the queued messages whose backoff has run out
+pub(crate) fn retry_pending(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    let now = now();
+    if state.pending.iter().all(|pending| pending.next_retry > now) {
+        return Ok(());
+    }
+    let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut state.pending)
+        .into_iter()
+        .partition(|pending| pending.next_retry <= now);
+    state.pending = waiting;
+
+    for pending in due {
+        // The message may have been deleted or expired while it waited
+        let Some(message) = state
+            .message_archive
+            .get(&pending.chat)
+            .and_then(|messages| {
+                messages
+                    .iter()
+                    .find(|message| message.id == pending.message_id)
+            })
+        else {
+            continue;
+        };
+        let resend = resend_request(&pending.chat, message);
+        send_tracked_chat_request(
+            state,
+            &pending.chat,
+            &resend,
+            &DeliveryContext {
+                chat: pending.chat.clone(),
+                message_id: pending.message_id,
+                attempts: pending.attempts,
+            },
+        )?;
+    }
+    save_state(our, state)
+}
+
+/// Record whether `node` answered us, telling the UI when that changes
+pub(crate) fn set_presence(our: &Address, state: &mut State, node: &str, online: bool) {
+    if online {
+        state.last_seen.insert(node.to_string(), now());
+    }
+    if state.presence.insert(node.to_string(), online) == Some(online) {
+        return;
+    }
+    let event = ChatEvent::PresenceChanged {
+        node: node.to_string(),
+        online,
+    };
+    if let Err(e) = push_to_channels(our, state, &event) {
+        print_to_terminal(0, &format!("testing: ws push failed: {:?}", e));
+    }
+}
+
+/// Ping every node we chat with once the interval is up. Nothing waits on the answers;
+/// they and any send errors come back to `handle_message` with a `PingContext`.
+pub(crate) fn ping_counterparties(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    let now = now();
+    if now < state.next_ping {
+        return Ok(());
+    }
+    let interval = state.presence_interval();
+    state.next_ping = now + interval;
+
+    let nodes: Vec<String> = state
+        .message_archive
+        .keys()
+        .filter(|chat| !chat.starts_with('#') && *chat != &our.node)
+        .filter(|chat| !state.blocked.contains(*chat))
+        .cloned()
+        .collect();
+    for node in nodes {
+        Request::new()
+            .target(chat_address(state, &node)?)
+            .ipc(serde_json::to_vec(&ChatRequest::Ping {
+                target: node.clone(),
+            })?)
+            .expects_response(state.send_timeout())
+            .context(serde_json::to_vec(&PingContext { ping: node })?)
+            .send()?;
+    }
+    set_timer(our, interval)
+}
+
+/// Send the scheduled messages that are due, through the ordinary `Send` path
+pub(crate) fn deliver_scheduled(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    let now = now();
+    if state
+        .scheduled
+        .iter()
+        .all(|scheduled| scheduled.deliver_at > now)
+    {
+        return Ok(());
+    }
+    let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut state.scheduled)
+        .into_iter()
+        .partition(|scheduled| scheduled.deliver_at <= now);
+    state.scheduled = waiting;
+    save_state(our, state)?;
+
+    for scheduled in due {
+        let send = ChatRequest::Send {
+            target: scheduled.target,
+            message: scheduled.message,
+            timestamp: None,
+            id: None,
+            reply_to: None,
+            format: MessageFormat::default(),
+            expires_in_seconds: None,
+            forwarded_from: None,
+            kind: MessageKind::Text,
+            seq: None,
+            client_key: None,
+            sealed: None,
+            metadata: HashMap::new(),
+            signature: None,
+        };
+        let response = handle_chat_request(our, state, our, &serde_json::to_vec(&send)?, false)?;
+        if let ChatResponse::Error { message, .. } = response {
+            print_to_terminal(
+                0,
+                &format!("testing: scheduled {} failed: {}", scheduled.id, message),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Send a chat request to this app on the target node without waiting for a response
+fn notify_chat_request(
+    state: &State,
+    target: &str,
+    chat_request: &ChatRequest,
+) -> anyhow::Result<()> {
+    Request::new()
+        .target(chat_address(state, target)?)
+        .ipc(serde_json::to_vec(chat_request)?)
+        .send()
+}
+
+/// Push to every open WebSocket. One that fails doesn't keep the rest from getting it.
+pub(crate) fn push_to_channels<T: Serialize>(
+    our: &Address,
+    state: &State,
+    body: &T,
+) -> anyhow::Result<()> {
+    let mut result = Ok(());
+    for channel_id in state.channels.keys() {
+        if let Err(e) = push_to_ui(our, *channel_id, body) {
+            result = Err(e);
+        }
+    }
+    result
+}
+
+/// Pushes larger than this are gzipped, in bytes of JSON
+const WS_COMPRESS_THRESHOLD: usize = 16 * 1024;
+
+pub(crate) fn push_to_ui<T: Serialize>(
+    our: &Address,
+    channel_id: u32,
+    body: &T,
+) -> anyhow::Result<()> {
+    let bytes = serde_json::to_vec(body)?;
+
+    // Big pushes, like history chunks, go out as a binary frame of gzipped JSON. The mime
+    // type tells the client to decompress; anything smaller isn't worth the trouble.
+    let (message_type, payload) = match bytes.len() > WS_COMPRESS_THRESHOLD {
+        true => (
+            WsMessageType::Binary,
+            Payload {
+                mime: Some("application/gzip".to_string()),
+                bytes: gzip(&bytes)?,
+            },
+        ),
+        false => (
+            WsMessageType::Text,
+            Payload {
+                mime: Some("application/json".to_string()),
+                bytes,
+            },
+        ),
+    };
+
+    // Send a WebSocket message to the http server in order to update the UI
+    send_ws_push(our.node.clone(), channel_id, message_type, payload)
+}
+
+fn gzip(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+/// Characters of a quoted message shown with a reply
+const QUOTE_LENGTH: usize = 100;
+
+/// The first `QUOTE_LENGTH` characters of `content`, marked when cut short
+fn excerpt(content: &str) -> String {
+    let mut excerpt: String = content.chars().take(QUOTE_LENGTH).collect();
+    if excerpt.len() < content.len() {
+        excerpt.push('…');
+    }
+    excerpt
+}
+
+/// The excerpt a reply to `reply_to` shows, looked up among the chat's `messages`
+pub(crate) fn quote(messages: &[ChatMessage], reply_to: &str) -> Quote {
+    match messages.iter().find(|message| message.id == reply_to) {
+        Some(quoted) if !quoted.deleted => Quote::Available {
+            author: quoted.author.clone(),
+            excerpt: excerpt(&quoted.content),
+        },
+        _ => Quote::Unavailable,
+    }
+}
+
+pub(crate) fn history(state: &State) -> ChatResponse {
+    let mut messages = state.message_archive.clone();
+    for (counterparty, chat) in messages.iter_mut() {
+        prepare_for_ui(&state.message_archive[counterparty], chat);
+    }
+
+    ChatResponse::History {
+        messages,
+        last_read: state.last_read.clone(),
+        pinned: state.pinned.clone(),
+        rooms: state.rooms.clone(),
+        groups: state.groups.clone(),
+        group_roles: state.group_roles.clone(),
+        closed: state.closed.clone(),
+        archived: state.archived.clone(),
+        aliases: state.aliases.clone(),
+        muted: state.muted.clone(),
+        drafts: state.drafts.clone(),
+        unread: state.unread.clone(),
+    }
+}
+
+/// Drop edit histories and fill in quotes, as `history` does, for copies of `originals`
+pub(crate) fn prepare_for_ui(originals: &[ChatMessage], messages: &mut [ChatMessage]) {
+    for message in messages {
+        message.edit_history.clear();
+        message.quoted = message
+            .reply_to
+            .as_ref()
+            .map(|reply_to| quote(originals, reply_to));
+    }
+}
+
+/// The pinned messages of every chat, or just the given one
+pub(crate) fn pinned_messages(state: &State, chat: Option<&String>) -> ChatResponse {
+    let messages = state
+        .pinned
+        .iter()
+        .filter(|(counterparty, _)| chat.is_none() || chat == Some(*counterparty))
+        .map(|(counterparty, pinned)| {
+            let messages = state
+                .message_archive
+                .get(counterparty)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let pinned = pinned
+                .iter()
+                .filter_map(|id| messages.iter().find(|message| &message.id == id))
+                .cloned()
+                .collect();
+            (counterparty.clone(), pinned)
+        })
+        .collect();
+
+    ChatResponse::Pinned { messages }
+}
+
+/// The `@node` tokens in `content`, without duplicates, in order of first appearance
+fn parse_mentions(content: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = vec![];
+    for token in content.split_whitespace() {
+        let Some(name) = token.strip_prefix('@') else {
+            continue;
+        };
+        // Drop punctuation around the name, as in "thanks @alice.uq!"
+        let name = name.trim_end_matches(|c: char| !c.is_alphanumeric());
+        if !name.is_empty() && !mentions.iter().any(|mention| mention == name) {
+            mentions.push(name.to_string());
+        }
+    }
+    mentions
+}
+
+/// Messages mentioning `node`, optionally limited to one chat
+pub(crate) fn mentioned_messages(state: &State, node: &str, chat: Option<&String>) -> ChatResponse {
+    let messages = state
+        .message_archive
+        .iter()
+        .filter(|(counterparty, _)| chat.is_none() || chat == Some(*counterparty))
+        .map(|(counterparty, messages)| {
+            let mentioned = messages
+                .iter()
+                .filter(|message| !message.deleted && message.mentions.iter().any(|m| m == node))
+                .cloned()
+                .collect::<Vec<_>>();
+            (counterparty.clone(), mentioned)
+        })
+        .filter(|(_, mentioned)| !mentioned.is_empty())
+        .collect();
+
+    ChatResponse::Mentions { messages }
+}
+
+fn chat_summary(state: &State, counterparty: &str, messages: &[ChatMessage]) -> ChatSummary {
+    let last_message = messages.iter().rev().find(|message| !message.deleted);
+    let stats = state.stats.get(counterparty);
+    ChatSummary {
+        counterparty: counterparty.to_string(),
+        preview: last_message
+            .map(|message| excerpt(&message.content))
+            .unwrap_or_default(),
+        last_message: last_message.cloned(),
+        last_activity: stats.and_then(|stats| stats.last_timestamp),
+        messages: stats.map_or(0, |stats| stats.messages),
+        unread: state.unread.get(counterparty).copied().unwrap_or(0),
+        muted: state.muted.contains_key(counterparty),
+        online: state.presence.get(counterparty).copied(),
+        last_seen: state.last_seen.get(counterparty).copied(),
+        last_active: state.their_last_seen.get(counterparty).copied(),
+        settings: state.chat_settings(counterparty),
+    }
+}
+
+pub(crate) fn conversations(state: &State) -> ChatResponse {
+    let mut chats: Vec<String> = state.message_archive.keys().cloned().collect();
+    chats.sort();
+
+    ChatResponse::Conversations { chats }
+}
+
+pub(crate) fn summaries(state: &State) -> ChatResponse {
+    let mut items: Vec<ChatSummary> = state
+        .message_archive
+        .iter()
+        .map(|(counterparty, messages)| chat_summary(state, counterparty, messages))
+        .collect();
+    items.sort_by_key(|item| {
+        std::cmp::Reverse(item.last_message.as_ref().map(|message| message.timestamp))
+    });
+
+    ChatResponse::Summaries {
+        items,
+        aliases: state.aliases.clone(),
+    }
+}
+
+pub(crate) fn scheduled_messages(state: &State) -> ChatResponse {
+    let mut scheduled = state.scheduled.clone();
+    scheduled.sort_by_key(|scheduled| scheduled.deliver_at);
+
+    ChatResponse::ScheduledMessages { scheduled }
+}
+
+pub(crate) fn starred_messages(state: &State) -> ChatResponse {
+    let mut messages: Vec<NewMessage> = state
+        .message_archive
+        .iter()
+        .flat_map(|(chat, messages)| {
+            messages
+                .iter()
+                .filter(|message| message.starred && !message.deleted)
+                .map(|message| NewMessage {
+                    chat: chat.clone(),
+                    chat_kind: ChatKind::of(chat),
+                    muted: state.muted.contains_key(chat),
+                    // Already seen, so there's nothing to notify of
+                    should_notify: false,
+                    display_name: state.aliases.get(&message.author).cloned(),
+                    message: message.clone(),
+                })
+        })
+        .collect();
+    messages.sort_by_key(|starred| starred.message.timestamp);
+
+    ChatResponse::Starred { messages }
+}
+
+/// Characters of context kept on either side of a match in search snippets
+const SNIPPET_CONTEXT: usize = 30;
+
+/// A short excerpt of `content` around the first match of the lowercased `query`
+fn snippet(content: &str, query: &str) -> String {
+    let lowercase = content.to_lowercase();
+    // Lowercasing can change byte lengths outside ASCII, so only trust offsets that line up
+    let start = match lowercase.find(query) {
+        Some(start) if lowercase.len() == content.len() && content.is_char_boundary(start) => start,
+        _ => 0,
+    };
+
+    let from = content[..start]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(i, _)| i);
+    let to = content[start..]
+        .char_indices()
+        .nth(query.chars().count() + SNIPPET_CONTEXT)
+        .map_or(content.len(), |(i, _)| start + i);
+
+    format!(
+        "{}{}{}",
+        if from > 0 { "…" } else { "" },
+        &content[from..to],
+        if to < content.len() { "…" } else { "" },
+    )
+}
+
+/// Case-insensitive substring search over the content and author of messages
+pub(crate) fn search(
+    state: &State,
+    query: &str,
+    counterparty: Option<&String>,
+    limit: Option<usize>,
+) -> ChatResponse {
+    if query.trim().is_empty() {
+        return ChatResponse::error(StatusCode::BAD_REQUEST, "empty search query");
+    }
+
+    let query = query.to_lowercase();
+    let hits = state
+        .message_archive
+        .iter()
+        .filter(|(chat, _)| counterparty.is_none() || counterparty == Some(*chat))
+        .flat_map(|(chat, messages)| {
+            let query = &query;
+            messages
+                .iter()
+                .enumerate()
+                .filter(move |(_, message)| {
+                    !message.deleted
+                        && (message.content.to_lowercase().contains(query)
+                            || message.author.to_lowercase().contains(query))
+                })
+                .map(move |(index, message)| SearchHit {
+                    counterparty: chat.clone(),
+                    message: message.clone(),
+                    index,
+                    snippet: snippet(&message.content, query),
+                })
+        })
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+
+    ChatResponse::SearchResults { hits }
+}
+
+/// The poll `poll_id` in `chat` if it still takes votes, or the error to answer with
+fn open_poll<'a>(
+    state: &'a mut State,
+    chat: &str,
+    poll_id: &str,
+) -> Result<&'a mut ChatMessage, Box<ChatResponse>> {
+    let Some(message) = find_message_mut(&mut state.message_archive, chat, poll_id) else {
+        return Err(Box::new(ChatResponse::error(
+            StatusCode::NOT_FOUND,
+            format!("no poll {} in chat with {}", poll_id, chat),
+        )));
+    };
+    match message.poll {
+        None => Err(Box::new(ChatResponse::error(
+            StatusCode::BAD_REQUEST,
+            format!("{} is not a poll", poll_id),
+        ))),
+        Some(Poll { closed: true, .. }) => Err(Box::new(ChatResponse::error(
+            StatusCode::CONFLICT,
+            format!("poll {} is closed", poll_id),
+        ))),
+        Some(_) => Ok(message),
+    }
+}
+
+/// Validate and switch to new settings, answering with them as they now are
+fn apply_settings(
+    our: &Address,
+    state: &mut State,
+    settings: Settings,
+) -> anyhow::Result<ChatResponse> {
+    if let Err(error) = settings.validate() {
+        return Ok(ChatResponse::error(StatusCode::BAD_REQUEST, error));
+    }
+    if settings.public_read && !state.settings.public_read {
+        bind_http_path("/public/messages", false, false)?;
+    }
+    state.settings = settings;
+
+    // A tighter retention applies to what we already have
+    let chats: Vec<String> = state.message_archive.keys().cloned().collect();
+    for chat in chats {
+        prune(state, &chat);
+    }
+    save_state(our, state)?;
+
+    Ok(ChatResponse::Settings(state.settings.clone()))
+}
+
+/// Drop the oldest messages of `chat` that fall outside the retention setting
+fn prune(state: &mut State, chat: &str) {
+    let Some(messages) = state.message_archive.get_mut(chat) else {
+        return;
+    };
+    let before = messages.len();
+    match state.settings.retention {
+        Some(Retention::MaxMessages(max)) => {
+            let excess = messages.len().saturating_sub(max);
+            messages.drain(..excess);
+        }
+        Some(Retention::MaxAgeSecs(max_age)) => {
+            let cutoff = now().saturating_sub(max_age);
+            messages.retain(|message| message.timestamp >= cutoff);
+        }
+        None => {}
+    }
+    if messages.len() < before {
+        state.recount_stats(chat);
+    }
+}
+
+/// Add a new message to a chat's archive and let the UI know about it
+pub(crate) fn store_message(
+    our: &Address,
+    state: &mut State,
+    counterparty: &str,
+    mut message: ChatMessage,
+    is_http: bool,
+) -> anyhow::Result<ChatResponse> {
+    let id = message.id.clone();
+
+    // A node we've never chatted with waits for us to accept before its messages
+    // reach the archive. Rooms have their own membership and aren't held.
+    if state.settings.hold_message_requests
+        && message.author != our.node
+        && !counterparty.starts_with('#')
+        && !state.message_archive.contains_key(counterparty)
+        && !state.contacts.contains(counterparty)
+    {
+        let held = state
+            .message_requests
+            .entry(counterparty.to_string())
+            .or_default();
+        if held.iter().any(|held| held.id == id) {
+            return Ok(ChatResponse::Ack);
+        }
+        held.push(message.clone());
+        save_state(our, state)?;
+
+        let event = ChatEvent::MessageRequest {
+            node: counterparty.to_string(),
+            message: Box::new(message),
+        };
+        if let Err(e) = push_to_channels(our, state, &event) {
+            print_to_terminal(0, &format!("testing: ws push failed: {:?}", e));
+        }
+        return Ok(ChatResponse::Ack);
+    }
+
+    let muted = state.is_muted(counterparty);
+
+    // Our own messages, the notes we add ourselves and muted chats are never unread
+    let unread = match message.author != our.node && !muted {
+        true => {
+            let count = state.unread.entry(counterparty.to_string()).or_default();
+            *count += 1;
+            Some(*count)
+        }
+        false => None,
+    };
+
+    // Anything new from the other side brings an archived chat back
+    let unarchived = message.author != our.node && state.archived.remove(counterparty);
+
+    state
+        .stats
+        .entry(counterparty.to_string())
+        .or_default()
+        .add(&message);
+    // Retreive the message archive for the counterparty, or create a new one if it doesn't exist
+    state
+        .message_archive
+        .entry(counterparty.to_string())
+        .or_default()
+        .push(message.clone());
+    prune(state, counterparty);
+    save_state(our, state)?;
+
+    message.quoted = message.reply_to.as_ref().map(|reply_to| {
+        quote(
+            state
+                .message_archive
+                .get(counterparty)
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+            reply_to,
+        )
+    });
+
+    // If this is an HTTP request, the calling function responds with the new message
+    if is_http {
+        return Ok(ChatResponse::Sent(Box::new(message)));
+    }
+
+    let mentions_us = message.author != our.node && message.mentions.contains(&our.node) && !muted;
+    let should_notify = message.author != our.node
+        && !muted
+        && match state.chat_settings(counterparty).notify {
+            Notify::All => true,
+            Notify::MentionsOnly => mentions_us,
+            Notify::None => false,
+        };
+    let author = message.author.clone();
+
+    // Every stored message is pushed exactly once. The message is already stored by now,
+    // so a UI that can't be reached mustn't keep the sender from getting its Ack.
+    let mut events = vec![ChatEvent::NewMessage(Box::new(NewMessage {
+        chat: counterparty.to_string(),
+        chat_kind: ChatKind::of(counterparty),
+        muted,
+        should_notify,
+        display_name: state.aliases.get(&message.author).cloned(),
+        message,
+    }))];
+    if let Some(count) = unread {
+        events.push(ChatEvent::UnreadChanged {
+            chat: counterparty.to_string(),
+            count,
+        });
+    }
+    if unarchived {
+        events.push(ChatEvent::ChatUnarchived {
+            chat: counterparty.to_string(),
+        });
+    }
+    if mentions_us {
+        events.push(ChatEvent::Mention {
+            chat: counterparty.to_string(),
+            message_id: id,
+            author,
+        });
+    }
+    for (channel_id, event) in route_events(state, counterparty, &events) {
+        if let Err(e) = push_to_ui(our, channel_id, event) {
+            print_to_terminal(0, &format!("testing: ws push failed: {:?}", e));
+        }
+    }
+
+    Ok(ChatResponse::Ack)
+}
+
+/// Which WebSocket gets each of the events about a message stored in `chat`.
+/// New messages only go to the WebSockets watching this chat, everything else to all.
+fn route_events<'a>(
+    state: &State,
+    chat: &str,
+    events: &'a [ChatEvent],
+) -> Vec<(u32, &'a ChatEvent)> {
+    events
+        .iter()
+        .flat_map(|event| {
+            let channels = match event {
+                ChatEvent::NewMessage(_) => state.channels_for(chat),
+                _ => state.channels.keys().copied().collect(),
+            };
+            channels
+                .into_iter()
+                .map(move |channel_id| (channel_id, event))
+        })
+        .collect()
+}
+
+pub(crate) fn handle_chat_request(
+    our: &Address,
+    state: &mut State,
+    source: &Address,
+    ipc: &[u8],
+    is_http: bool,
+) -> anyhow::Result<ChatResponse> {
+    handle_chat_request_with_payload(our, state, source, ipc, is_http, None)
+}
+
+/// Like `handle_chat_request`, with attachment bytes that didn't come as the payload of
+/// the message being handled, like the audio in a voice note frame
+pub(crate) fn handle_chat_request_with_payload(
+    our: &Address,
+    state: &mut State,
+    source: &Address,
+    ipc: &[u8],
+    is_http: bool,
+    mut payload: Option<Payload>,
+) -> anyhow::Result<ChatResponse> {
+    print_to_terminal(0, "3");
+    let mut chat_request = match serde_json::from_slice::<ChatRequest>(ipc) {
+        Ok(chat_request) => chat_request,
+        Err(e) => {
+            return Ok(ChatResponse::error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid chat request: {}", e),
+            ));
+        }
+    };
+    print_to_terminal(0, "4");
+
+    // Anything we do ourselves counts as being active, except the automatic presence checks
+    if source.node == our.node
+        && !matches!(
+            chat_request,
+            ChatRequest::Ping { .. } | ChatRequest::LastSeen { .. }
+        )
+    {
+        state.last_active = now();
+    }
+
+    // Decrypt sealed content before anything else looks at the message
+    if let ChatRequest::Send {
+        ref mut message,
+        sealed: Some(ref sealed),
+        ..
+    }
+    | ChatRequest::Edit {
+        new_content: ref mut message,
+        sealed: Some(ref sealed),
+        ..
+    } = chat_request
+    {
+        let Some(plaintext) = state
+            .chat_keys
+            .get(&source.node)
+            .and_then(|keys| open_sealed(keys, sealed))
+        else {
+            return Ok(ChatResponse::error(
+                StatusCode::BAD_REQUEST,
+                "cannot decrypt message, keys need to be exchanged again",
+            ));
+        };
+        *message = plaintext;
+    }
+
+    // Nothing from a blocked node reaches us, whether messages, typing, reactions or edits.
+    // They get the same answer as everyone else, so they can't tell.
+    // Nothing else is checked first, since any other error would give it away.
+    if source.node != our.node
+        && (state.blocked.contains(&source.node) || state.ignored.contains(&source.node))
+    {
+        print_to_terminal(
+            0,
+            &format!("testing: dropped request from blocked {}", source.node),
+        );
+        return Ok(match chat_request {
+            ChatRequest::Send { id: Some(id), .. } => ChatResponse::Received { request_id: id },
+            _ => ChatResponse::Ack,
+        });
+    }
+
+    // Signatures are optional, but a bad one means the message isn't from who it claims
+    let mut verified = false;
+    if let ChatRequest::Send {
+        ref target,
+        ref message,
+        timestamp,
+        signature: Some(ref signature),
+        ..
+    } = chat_request
+    {
+        if target == &our.node && source.node != our.node {
+            if let Err(e) = verify_signature(state, &source.node, message, timestamp, signature) {
+                return Ok(ChatResponse::error(StatusCode::FORBIDDEN, e));
+            }
+            verified = true;
+        }
+    }
+
+    // Applies to our own messages and to those from other nodes alike
+    // Catch malformed targets before they become archive keys or addresses
+    if let Some(target) = chat_request
+        .targets()
+        .into_iter()
+        .find(|target| !is_valid_node_name(target))
+    {
+        return Ok(ChatResponse::error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid node name {:?}", target),
+        ));
+    }
+
+    if let ChatRequest::Send {
+        ref metadata,
+        ref forwarded_from,
+        ..
+    } = chat_request
+    {
+        if serde_json::to_vec(metadata)?.len() > MAX_METADATA_SIZE {
+            return Ok(ChatResponse::error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("metadata is limited to {} bytes", MAX_METADATA_SIZE),
+            ));
+        }
+        if forwarded_from.as_ref().is_some_and(|forwarded_from| {
+            forwarded_from.author.len() > MAX_FORWARDED_NAME_LENGTH
+                || forwarded_from.chat.len() > MAX_FORWARDED_NAME_LENGTH
+        }) {
+            return Ok(ChatResponse::error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "forwarded names are limited to {} bytes",
+                    MAX_FORWARDED_NAME_LENGTH
+                ),
+            ));
+        }
+    }
+
+    if let ChatRequest::React { ref emoji, .. } = chat_request {
+        if emoji.is_empty() || emoji.len() > MAX_EMOJI_LENGTH {
+            return Ok(ChatResponse::error(
+                StatusCode::BAD_REQUEST,
+                format!("reactions must be 1 to {} bytes", MAX_EMOJI_LENGTH),
+            ));
+        }
+    }
+
+    for content in chat_request.contents() {
+        if content.len() > state.max_message_length() {
+            return Ok(ChatResponse::error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "messages are limited to {} bytes",
+                    state.max_message_length()
+                ),
+            ));
+        }
+    }
+
+    // Nothing new goes into a closed chat, whoever it's from
+    let new_message_target = match chat_request {
+        ChatRequest::Send { ref target, .. }
+        | ChatRequest::SendAttachment { ref target, .. }
+        | ChatRequest::CreatePoll { ref target, .. }
+        | ChatRequest::SendLocation { ref target, .. }
+        | ChatRequest::SendBatch { ref target, .. } => Some(target.clone()),
+        ChatRequest::SendToRoom { ref room, .. } => Some(room_key(room)),
+        _ => None,
+    };
+    if let Some(ref target) = new_message_target {
+        let counterparty = if target == &our.node {
+            &source.node
+        } else {
+            target
+        };
+        if state.closed.contains(counterparty) {
+            return Ok(ChatResponse::error(StatusCode::FORBIDDEN, "closed"));
+        }
+        // We can't write to someone we've blocked either
+        if source.node == our.node && state.blocked.contains(target) {
+            return Ok(ChatResponse::error(StatusCode::FORBIDDEN, "blocked"));
+        }
+    }
+
+    // Only other nodes are limited; what we send from the UI never is. Each message of a
+    // batch counts again as it's handled, on top of the batch itself.
+    let new_messages =
+        new_message_target.is_some() || matches!(chat_request, ChatRequest::ReceiveBatch { .. });
+    if source.node != our.node && new_messages && state.rate_limited(&source.node) {
+        print_to_terminal(
+            0,
+            &format!("testing: rate limited message from {}", source.node),
+        );
+        return Ok(ChatResponse::error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many messages, slow down",
+        ));
+    }
+
+    // Filter what we send and what we're sent alike, before it's stored or forwarded
+    let filtered = match apply_filter(&state.filter, &mut chat_request) {
+        Ok(filtered) => filtered,
+        Err((code, reason)) => return Ok(ChatResponse::error(code, reason)),
+    };
+
+    match chat_request {
+        ChatRequest::Send {
+            ref target,
+            ref message,
+            timestamp,
+            ref id,
+            ref reply_to,
+            format,
+            expires_in_seconds,
+            ref forwarded_from,
+            kind,
+            seq,
+            ref client_key,
+            ref sealed,
+            ref metadata,
+            ..
+        } => {
+            print_to_terminal(0, "5");
+            // counterparty will be the other node in the chat with us
+            let (counterparty, author) = if target == &our.node {
+                (&source.node, source.node.clone())
+            } else {
+                (target, our.node.clone())
+            };
+
+            // A repeated client key gets the original message back, unless that one failed
+            // and this is the retry
+            if let Some(id) = client_key
+                .as_ref()
+                .and_then(|key| state.seen_client_key(counterparty, &author, key))
+                .cloned()
+            {
+                if !find_message_mut(&mut state.message_archive, counterparty, &id)
+                    .is_some_and(|message| message.status == DeliveryStatus::Failed)
+                {
+                    return Ok(ChatResponse::AlreadySent { id });
+                }
+            }
+
+            // Replies we send must quote a message we know of; the counterparty may have
+            // quoted one we don't have, so accept those as they are
+            if let Some(reply_to) = reply_to {
+                if target != &our.node
+                    && find_message_mut(&mut state.message_archive, counterparty, reply_to)
+                        .is_none()
+                {
+                    return Ok(ChatResponse::error(
+                        StatusCode::NOT_FOUND,
+                        format!("no message {} in chat with {}", reply_to, counterparty),
+                    ));
+                }
+            }
+
+            // Keep the sender's timestamp for messages from other nodes so both sides agree
+            let timestamp = match timestamp {
+                Some(timestamp) if target == &our.node => timestamp,
+                _ => now(),
+            };
+            let has_failed = |state: &mut State, id: &MessageId| {
+                find_message_mut(&mut state.message_archive, counterparty, id)
+                    .is_some_and(|message| message.status == DeliveryStatus::Failed)
+            };
+            let id = match id {
+                Some(id) if target == &our.node => id.clone(),
+                // Retrying a message that didn't get through reuses its id
+                Some(id) if has_failed(state, id) => id.clone(),
+                _ => state.new_message_id(&author),
+            };
+            let retrying = has_failed(state, &id);
+
+            // A retried delivery carries the same id, so don't store it twice
+            let existing = find_message_mut(&mut state.message_archive, counterparty, &id);
+            let existing_seq = existing.as_ref().and_then(|message| message.seq);
+            if !retrying && existing.is_some() {
+                return Ok(match target == &our.node {
+                    true => ChatResponse::Received { request_id: id },
+                    false => ChatResponse::Ack,
+                });
+            }
+
+            let seq = if target == &our.node {
+                match seq.map(|seq| state.track_seq(counterparty, seq)) {
+                    Some(SeqCheck::Duplicate) => return Ok(ChatResponse::Ack),
+                    Some(SeqCheck::Gap { from_seq, to_seq }) => {
+                        print_to_terminal(
+                            0,
+                            &format!(
+                                "testing: missed {}..={} from {}, asking again",
+                                from_seq, to_seq, counterparty
+                            ),
+                        );
+                        notify_chat_request(
+                            state,
+                            counterparty,
+                            &ChatRequest::Resend { from_seq, to_seq },
+                        )?;
+                    }
+                    Some(SeqCheck::New) | None => {}
+                }
+                seq
+            } else if retrying {
+                existing_seq
+            } else {
+                Some(state.new_seq(counterparty))
+            };
+
+            // The raw message is forwarded and each side sanitizes what it stores,
+            // so content is never escaped twice and never trusted from the other node
+            let content = sanitize_content(message);
+            let raw_content = state.settings.trusted_content.then(|| message.clone());
+
+            // Don't wait on a node we know is down; the message goes straight to the retry queue
+            let offline = target != &our.node && state.presence.get(target) == Some(&false);
+
+            // Mentions are only recorded; nobody but the counterparty is sent the message
+            let new_message = ChatMessage {
+                id: id.clone(),
+                author,
+                content,
+                timestamp,
+                reply_to: reply_to.clone(),
+                format,
+                mentions: parse_mentions(message),
+                expires_at: expires_in_seconds.map(|ttl| now() + ttl),
+                forwarded_from: forwarded_from
+                    .as_ref()
+                    .map(|forwarded_from| forwarded_from.map(sanitize_content)),
+                seq,
+                encrypted: sealed.is_some()
+                    || (target != &our.node && state.chat_keys.contains_key(counterparty)),
+                metadata: map_metadata(metadata, &sanitize_content),
+                filtered,
+                verified,
+                raw_content,
+                // Images and files only come with attachments
+                kind: match kind {
+                    MessageKind::System | MessageKind::Notice if target != &our.node => kind,
+                    _ => MessageKind::Text,
+                },
+                status: match target == &our.node {
+                    true => DeliveryStatus::Delivered,
+                    false => DeliveryStatus::Pending,
+                },
+                ..ChatMessage::default()
+            };
+
+            print_to_terminal(0, "6");
+            // If the target is not us, send a request to the target
+
+            if target != &our.node && !offline {
+                print_to_terminal(0, &format!("new message from {}: {}", source.node, message));
+
+                send_tracked_chat_request(
+                    state,
+                    target,
+                    &ChatRequest::Send {
+                        target: target.clone(),
+                        message: message.clone(),
+                        timestamp: Some(timestamp),
+                        id: Some(id.clone()),
+                        reply_to: reply_to.clone(),
+                        format,
+                        expires_in_seconds,
+                        forwarded_from: forwarded_from.clone(),
+                        kind,
+                        seq,
+                        client_key: client_key.clone(),
+                        sealed: None,
+                        metadata: metadata.clone(),
+                        signature: None,
+                    },
+                    &DeliveryContext {
+                        chat: counterparty.clone(),
+                        message_id: id.clone(),
+                        attempts: 0,
+                    },
+                )?;
+            }
+
+            if offline {
+                state.pending.push(PendingMessage {
+                    chat: counterparty.clone(),
+                    message_id: id.clone(),
+                    attempts: 0,
+                    next_retry: now() + RETRY_BACKOFF_SECS,
+                });
+                set_timer(our, RETRY_BACKOFF_SECS)?;
+            }
+
+            // Our message went out, so whatever was being drafted for this chat is done
+            if target != &our.node {
+                state.drafts.remove(counterparty);
+            }
+
+            // A retry replaces the failed copy
+            if retrying {
+                if let Some(messages) = state.message_archive.get_mut(counterparty) {
+                    messages.retain(|message| message.id != id);
+                }
+                state.recount_stats(counterparty);
+            }
+
+            if let Some(ttl) = expires_in_seconds {
+                set_timer(our, ttl)?;
+            }
+
+            if let Some(key) = client_key {
+                state.remember_client_key(counterparty, &new_message.author, key, &new_message.id);
+            }
+
+            let response = store_message(our, state, counterparty, new_message, is_http)?;
+            match response {
+                ChatResponse::Ack if target == &our.node => {
+                    Ok(ChatResponse::Received { request_id: id })
+                }
+                response => Ok(response),
+            }
+        }
+        ChatRequest::SendAttachment {
+            ref target,
+            ref filename,
+            ref mime,
+            timestamp,
+            ref id,
+        } => {
+            let Some(payload) = payload.take().or_else(get_payload) else {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "missing attachment bytes",
+                ));
+            };
+            if payload.bytes.len() > state.max_attachment_size() {
+                return Ok(ChatResponse::error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!(
+                        "attachments are limited to {} bytes",
+                        state.max_attachment_size()
+                    ),
+                ));
+            }
+            let mime = match mime.is_empty() {
+                false => mime,
+                true => payload
+                    .mime
+                    .as_ref()
+                    .map_or("application/octet-stream", String::as_str),
+            };
+
+            let (counterparty, author) = if target == &our.node {
+                (&source.node, source.node.clone())
+            } else {
+                (target, our.node.clone())
+            };
+            let timestamp = match timestamp {
+                Some(timestamp) if target == &our.node => timestamp,
+                _ => now(),
+            };
+            let id = match id {
+                Some(id) if target == &our.node => id.clone(),
+                _ => state.new_message_id(&author),
+            };
+            if find_message_mut(&mut state.message_archive, counterparty, &id).is_some() {
+                return Ok(ChatResponse::Ack);
+            }
+
+            if target != &our.node {
+                let forwarded = ChatRequest::SendAttachment {
+                    target: target.clone(),
+                    filename: filename.clone(),
+                    mime: mime.to_string(),
+                    timestamp: Some(timestamp),
+                    id: Some(id.clone()),
+                };
+                if let error @ ChatResponse::Error { .. } = forward_chat_request_with_payload(
+                    state,
+                    target,
+                    &forwarded,
+                    Some(payload.clone()),
+                )? {
+                    return Ok(error);
+                }
+            }
+
+            open_file(&attachment_path(our, &id)?, true)?.write(&payload.bytes)?;
+
+            // Corrupt images are still stored, just without a preview
+            let is_image = mime.starts_with("image/");
+            let is_audio = mime.starts_with("audio/");
+            let thumbnail = match is_image {
+                true => make_thumbnail(&payload.bytes),
+                false => None,
+            };
+            if let Some(thumbnail) = &thumbnail {
+                open_file(&thumbnail_path(our, &id)?, true)?.write(thumbnail)?;
+            }
+
+            let new_message = ChatMessage {
+                id,
+                author,
+                timestamp,
+                attachment: Some(AttachmentMeta {
+                    filename: sanitize_content(filename),
+                    mime: mime.to_string(),
+                    size: payload.bytes.len() as u64,
+                    thumbnail: thumbnail.is_some(),
+                }),
+                kind: if is_image {
+                    MessageKind::Image
+                } else if is_audio {
+                    MessageKind::Audio
+                } else {
+                    MessageKind::File
+                },
+                ..ChatMessage::default()
+            };
+
+            store_message(our, state, counterparty, new_message, is_http)
+        }
+        ChatRequest::Edit {
+            ref target,
+            ref message_id,
+            ref new_content,
+            ..
+        } => {
+            // Only the author of a message may edit it
+            let (counterparty, requester) = if target == &our.node {
+                (&source.node, &source.node)
+            } else {
+                (target, &our.node)
+            };
+
+            let Some(message) =
+                find_message_mut(&mut state.message_archive, counterparty, message_id)
+            else {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no message {} in chat with {}", message_id, counterparty),
+                ));
+            };
+            if &message.author != requester {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "cannot edit another node's message",
+                ));
+            }
+
+            // If the target is not us, the edit has to be applied on their side too,
+            // as sealed as the message it changes
+            if target != &our.node {
+                if let error @ ChatResponse::Error { .. } =
+                    forward_chat_request(state, target, &seal_send(state, chat_request.clone())?)?
+                {
+                    return Ok(error);
+                }
+            }
+
+            let max_edit_history = state.max_edit_history();
+            let trusted_content = state.settings.trusted_content;
+            if let Some(message) =
+                find_message_mut(&mut state.message_archive, counterparty, message_id)
+            {
+                if let Some(stats) = state.stats.get_mut(counterparty) {
+                    stats.characters = (stats.characters + new_content.chars().count())
+                        .saturating_sub(message.content.chars().count());
+                }
+                let edited_at = now();
+                message.edit_history.push(Revision {
+                    timestamp: edited_at,
+                    content: std::mem::replace(&mut message.content, sanitize_content(new_content)),
+                });
+                if trusted_content {
+                    message.raw_content = Some(new_content.clone());
+                }
+                let excess = message.edit_history.len().saturating_sub(max_edit_history);
+                message.edit_history.drain(..excess);
+                message.mentions = parse_mentions(new_content);
+                message.edited_at = Some(edited_at);
+                message.edited = true;
+            }
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::MessageEdited {
+                    chat: counterparty.clone(),
+                    message_id: message_id.clone(),
+                    content: sanitize_content(new_content),
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Delete {
+            ref target,
+            ref message_id,
+            index,
+        } => {
+            // Only the author of a message may delete it
+            let (counterparty, requester) = if target == &our.node {
+                (&source.node, &source.node)
+            } else {
+                (target, &our.node)
+            };
+
+            let message = match (message_id, index) {
+                (Some(message_id), _) => {
+                    find_message_mut(&mut state.message_archive, counterparty, message_id)
+                }
+                (None, Some(index)) => state
+                    .message_archive
+                    .get_mut(counterparty)
+                    .and_then(|messages| messages.get_mut(index)),
+                (None, None) => {
+                    return Ok(ChatResponse::error(
+                        StatusCode::BAD_REQUEST,
+                        "delete needs a message_id or an index",
+                    ));
+                }
+            };
+            let Some(message) = message else {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no such message in chat with {}", counterparty),
+                ));
+            };
+            if &message.author != requester {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "cannot delete another node's message",
+                ));
+            }
+            let message_id = message.id.clone();
+
+            // If the target is not us, the delete has to be applied on their side too.
+            // Indices can differ between the two archives, so always forward by id.
+            if target != &our.node {
+                if let error @ ChatResponse::Error { .. } = forward_chat_request(
+                    state,
+                    target,
+                    &ChatRequest::Delete {
+                        target: target.clone(),
+                        message_id: Some(message_id.clone()),
+                        index: None,
+                    },
+                )? {
+                    return Ok(error);
+                }
+            }
+
+            // Keep the entry as a tombstone so ordering and ids stay stable
+            if let Some(message) =
+                find_message_mut(&mut state.message_archive, counterparty, &message_id)
+            {
+                if !message.deleted {
+                    if let Some(stats) = state.stats.get_mut(counterparty) {
+                        stats.remove(message);
+                    }
+                }
+                message.content.clear();
+                message.edit_history.clear();
+                message.deleted = true;
+            }
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::MessageDeleted {
+                    chat: counterparty.clone(),
+                    message_id,
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::React {
+            ref target,
+            ref message_id,
+            ref emoji,
+        } => {
+            let (counterparty, reactor) = if target == &our.node {
+                (&source.node, &source.node)
+            } else {
+                (target, &our.node)
+            };
+
+            if find_message_mut(&mut state.message_archive, counterparty, message_id).is_none() {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no message {} in chat with {}", message_id, counterparty),
+                ));
+            }
+
+            // If the target is not us, the reaction has to be applied on their side too
+            if target != &our.node {
+                if let error @ ChatResponse::Error { .. } =
+                    forward_chat_request(state, target, &chat_request)?
+                {
+                    return Ok(error);
+                }
+            }
+
+            let Some(message) =
+                find_message_mut(&mut state.message_archive, counterparty, message_id)
+            else {
+                return Ok(ChatResponse::Ack);
+            };
+
+            // Reacting again with the same emoji toggles the reaction off
+            let emoji = sanitize_content(emoji);
+            let reactors = message.reactions.entry(emoji.clone()).or_default();
+            if let Some(position) = reactors.iter().position(|node| node == reactor) {
+                reactors.remove(position);
+            } else {
+                reactors.push(reactor.clone());
+            }
+            if reactors.is_empty() {
+                message.reactions.remove(&emoji);
+            }
+            let reactions = message.reactions.clone();
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::ReactionAdded {
+                    chat: counterparty.clone(),
+                    message_id: message_id.clone(),
+                    reactions,
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::MarkRead {
+            ref target,
+            ref up_to_message_id,
+        } => {
+            let counterparty = if target == &our.node {
+                &source.node
+            } else {
+                target
+            };
+
+            // Opening a chat clears its unread count
+            if target != &our.node && state.unread.remove(counterparty).is_some() {
+                save_state(our, state)?;
+                push_to_channels(
+                    our,
+                    state,
+                    &ChatEvent::UnreadChanged {
+                        chat: counterparty.clone(),
+                        count: 0,
+                    },
+                )?;
+            }
+
+            let messages = state
+                .message_archive
+                .get(counterparty)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            // Our own unknown ids are clamped to the newest message we know of, while
+            // receipts for messages we don't have are ignored
+            let position = match up_to_message_id {
+                Some(up_to_message_id) => messages
+                    .iter()
+                    .position(|message| &message.id == up_to_message_id)
+                    .or(match target == &our.node {
+                        true => None,
+                        false => messages.len().checked_sub(1),
+                    }),
+                None => messages.len().checked_sub(1),
+            };
+            let Some(position) = position else {
+                return Ok(ChatResponse::Ack);
+            };
+            let up_to_message_id = messages[position].id.clone();
+
+            let marker = state.last_read.entry(counterparty.clone()).or_default();
+            let current = if target == &our.node {
+                &mut marker.by_them
+            } else {
+                &mut marker.by_us
+            };
+            // Never move a marker backwards
+            let current_position = current
+                .as_ref()
+                .and_then(|id| messages.iter().position(|message| &message.id == id));
+            if current_position.is_some_and(|current_position| current_position > position) {
+                return Ok(ChatResponse::Ack);
+            }
+            *current = Some(up_to_message_id.clone());
+            save_state(our, state)?;
+
+            if target != &our.node {
+                // Let the counterparty know how far we've read
+                if let error @ ChatResponse::Error { .. } = forward_chat_request(
+                    state,
+                    target,
+                    &ChatRequest::MarkRead {
+                        target: target.clone(),
+                        up_to_message_id: Some(up_to_message_id),
+                    },
+                )? {
+                    return Ok(error);
+                }
+            } else {
+                // Everything of ours up to the receipt has now been read
+                let mut read = vec![];
+                if let Some(messages) = state.message_archive.get_mut(counterparty) {
+                    for message in messages[..=position]
+                        .iter_mut()
+                        .filter(|message| message.author == our.node)
+                        .filter(|message| message.status != DeliveryStatus::Read)
+                    {
+                        message.status = DeliveryStatus::Read;
+                        read.push(message.id.clone());
+                    }
+                }
+                if !read.is_empty() {
+                    save_state(our, state)?;
+                }
+                for message_id in read {
+                    push_to_channels(
+                        our,
+                        state,
+                        &ChatEvent::DeliveryUpdate {
+                            chat: counterparty.clone(),
+                            message_id,
+                            status: DeliveryStatus::Read,
+                        },
+                    )?;
+                }
+
+                push_to_channels(
+                    our,
+                    state,
+                    &ChatEvent::ReadReceipt {
+                        chat: counterparty.clone(),
+                        up_to_message_id,
+                    },
+                )?;
+            }
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Clear { ref counterparty } => {
+            // Only we get to wipe our own history
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only local processes may clear chats",
+                ));
+            }
+
+            match counterparty {
+                Some(counterparty) => {
+                    state.message_archive.remove(counterparty);
+                    state.stats.remove(counterparty);
+                    state.last_read.remove(counterparty);
+                    state.pinned.remove(counterparty);
+                    state.unread.remove(counterparty);
+                }
+                None => {
+                    state.message_archive.clear();
+                    state.stats.clear();
+                    state.last_read.clear();
+                    state.pinned.clear();
+                    state.unread.clear();
+                }
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Typing {
+            ref target,
+            is_typing,
+        } => {
+            if target != &our.node {
+                notify_chat_request(state, target, &chat_request)?;
+            } else {
+                push_to_channels(
+                    our,
+                    state,
+                    &ChatEvent::Typing {
+                        chat: source.node.clone(),
+                        author: source.node.clone(),
+                        is_typing,
+                        timestamp: now(),
+                    },
+                )?;
+            }
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Star {
+            ref chat,
+            ref message_id,
+        }
+        | ChatRequest::Unstar {
+            ref chat,
+            ref message_id,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "stars are local to this node",
+                ));
+            }
+            let starred = matches!(chat_request, ChatRequest::Star { .. });
+            let Some(message) = find_message_mut(&mut state.message_archive, chat, message_id)
+            else {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no message {} in chat with {}", message_id, chat),
+                ));
+            };
+            message.starred = starred;
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::MessageStarred {
+                    chat: chat.clone(),
+                    message_id: message_id.clone(),
+                    starred,
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Pin {
+            ref target,
+            ref message_id,
+        }
+        | ChatRequest::Unpin {
+            ref target,
+            ref message_id,
+        } => {
+            let pin = matches!(chat_request, ChatRequest::Pin { .. });
+            let counterparty = if target == &our.node {
+                &source.node
+            } else {
+                target
+            };
+
+            if pin
+                && find_message_mut(&mut state.message_archive, counterparty, message_id).is_none()
+            {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no message {} in chat with {}", message_id, counterparty),
+                ));
+            }
+
+            // If the target is not us, keep their pins in sync with ours
+            if target != &our.node {
+                if let error @ ChatResponse::Error { .. } =
+                    forward_chat_request(state, target, &chat_request)?
+                {
+                    return Ok(error);
+                }
+            }
+
+            let pinned = state.pinned.entry(counterparty.clone()).or_default();
+            pinned.retain(|id| id != message_id);
+            if pin {
+                pinned.push(message_id.clone());
+            }
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::MessagePinned {
+                    chat: counterparty.clone(),
+                    message_id: message_id.clone(),
+                    pinned: pin,
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Search {
+            ref query,
+            ref counterparty,
+            limit,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can search our chats",
+                ));
+            }
+            Ok(search(state, query, counterparty.as_ref(), limit))
+        }
+        ChatRequest::Forward {
+            ref from_chat,
+            ref message_id,
+            ref to_target,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can forward our messages",
+                ));
+            }
+            let Some(original) = state
+                .message_archive
+                .get(from_chat)
+                .and_then(|messages| messages.iter().find(|message| &message.id == message_id))
+                .filter(|message| !message.deleted)
+            else {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no message {} in chat with {}", message_id, from_chat),
+                ));
+            };
+            if original.attachment.is_some() {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "cannot forward attachments",
+                ));
+            }
+
+            // A forward of a forward still credits the original author
+            let forwarded_from = match original.forwarded_from {
+                Some(ref forwarded_from) => forwarded_from.map(unsanitize),
+                None => ForwardedFrom {
+                    author: original.author.clone(),
+                    chat: from_chat.clone(),
+                },
+            };
+            let send = ChatRequest::Send {
+                target: to_target.clone(),
+                message: unsanitize(&original.content),
+                timestamp: None,
+                id: None,
+                reply_to: None,
+                format: original.format,
+                expires_in_seconds: None,
+                forwarded_from: Some(forwarded_from),
+                kind: MessageKind::Text,
+                seq: None,
+                client_key: None,
+                sealed: None,
+                metadata: map_metadata(&original.metadata, &unsanitize),
+                signature: None,
+            };
+
+            // From here on it's an ordinary send
+            handle_chat_request(our, state, source, &serde_json::to_vec(&send)?, is_http)
+        }
+        ChatRequest::Schedule {
+            ref target,
+            ref message,
+            deliver_at,
+        } if source.node == our.node => {
+            state.next_scheduled_id += 1;
+            let id = format!("scheduled:{}", state.next_scheduled_id);
+            state.scheduled.push(ScheduledMessage {
+                id: id.clone(),
+                target: target.clone(),
+                message: message.clone(),
+                deliver_at,
+            });
+            save_state(our, state)?;
+            set_timer(our, deliver_at.saturating_sub(now()))?;
+
+            Ok(ChatResponse::Scheduled { id })
+        }
+        ChatRequest::ListScheduled if source.node == our.node => Ok(scheduled_messages(state)),
+        ChatRequest::CancelScheduled { ref id } if source.node == our.node => {
+            let before = state.scheduled.len();
+            state.scheduled.retain(|scheduled| &scheduled.id != id);
+            if state.scheduled.len() == before {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no scheduled message {}", id),
+                ));
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Schedule { .. }
+        | ChatRequest::ListScheduled
+        | ChatRequest::CancelScheduled { .. } => Ok(ChatResponse::error(
+            StatusCode::FORBIDDEN,
+            "schedules are local to this node",
+        )),
+        ChatRequest::ExchangeKeys { ref target } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "keys are exchanged with KeyExchange",
+                ));
+            }
+            let version = state
+                .chat_keys
+                .get(target)
+                .map_or(1, |keys| keys.current + 1);
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let public_key = PublicKey::from(&secret).to_bytes();
+
+            let their_public_key = match forward_chat_request(
+                state,
+                target,
+                &ChatRequest::KeyExchange {
+                    public_key,
+                    version,
+                },
+            )? {
+                ChatResponse::KeyExchange { public_key } => public_key,
+                error @ ChatResponse::Error { .. } => return Ok(error),
+                _ => {
+                    return Ok(ChatResponse::error(
+                        StatusCode::BAD_GATEWAY,
+                        format!("{} did not exchange keys", target),
+                    ))
+                }
+            };
+
+            let key = derive_key(&secret.diffie_hellman(&PublicKey::from(their_public_key)));
+            let keys = state.chat_keys.entry(target.clone()).or_default();
+            keys.keys.insert(version, key);
+            keys.current = version;
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::KeyExchange {
+            public_key,
+            version,
+        } => {
+            if source.node == our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "use ExchangeKeys to start a key exchange",
+                ));
+            }
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let our_public_key = PublicKey::from(&secret).to_bytes();
+
+            // Earlier versions stay around for messages sealed before this rotation
+            let key = derive_key(&secret.diffie_hellman(&PublicKey::from(public_key)));
+            let keys = state.chat_keys.entry(source.node.clone()).or_default();
+            keys.keys.insert(version, key);
+            keys.current = version;
+            save_state(our, state)?;
+
+            Ok(ChatResponse::KeyExchange {
+                public_key: our_public_key,
+            })
+        }
+        ChatRequest::SendBatch {
+            ref target,
+            ref messages,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "batches arrive as ReceiveBatch",
+                ));
+            }
+            if target == &our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "cannot send a batch to ourselves",
+                ));
+            }
+
+            // Store every message as pending, pushing each to the UI on its own
+            let mut sends = vec![];
+            let mut ids = vec![];
+            for message in messages {
+                let new_message = ChatMessage {
+                    id: state.new_message_id(&our.node),
+                    author: our.node.clone(),
+                    content: sanitize_content(message),
+                    timestamp: now(),
+                    mentions: parse_mentions(message),
+                    seq: Some(state.new_seq(target)),
+                    status: DeliveryStatus::Pending,
+                    encrypted: state.chat_keys.contains_key(target),
+                    ..ChatMessage::default()
+                };
+                let send = ChatRequest::Send {
+                    target: target.clone(),
+                    message: message.clone(),
+                    timestamp: Some(new_message.timestamp),
+                    id: Some(new_message.id.clone()),
+                    reply_to: None,
+                    format: MessageFormat::default(),
+                    expires_in_seconds: None,
+                    forwarded_from: None,
+                    kind: MessageKind::Text,
+                    seq: new_message.seq,
+                    client_key: None,
+                    sealed: None,
+                    metadata: HashMap::new(),
+                    signature: None,
+                };
+                sends.push(seal_send(state, send)?);
+                ids.push(new_message.id.clone());
+                store_message(our, state, target, new_message, false)?;
+            }
+            state.drafts.remove(target);
+            let statuses: HashMap<MessageId, DeliveryStatus> =
+                match forward_chat_request(state, target, &ChatRequest::ReceiveBatch { sends })? {
+                    ChatResponse::BatchResult { results } => results
+                        .into_iter()
+                        .map(|result| (result.id, result.status))
+                        .collect(),
+                    // Nothing got through, so every message goes to the retry queue
+                    ChatResponse::Error { code, .. }
+                        if code == StatusCode::GATEWAY_TIMEOUT.as_u16() =>
+                    {
+                        set_presence(our, state, target, false);
+                        HashMap::new()
+                    }
+                    _ => ids
+                        .iter()
+                        .map(|id| (id.clone(), DeliveryStatus::Failed))
+                        .collect(),
+                };
+
+            let mut results = vec![];
+            for id in ids {
+                let context = DeliveryContext {
+                    chat: target.clone(),
+                    message_id: id.clone(),
+                    attempts: 0,
+                };
+                let status = match statuses.get(&id) {
+                    Some(status) => {
+                        update_delivery_status(our, state, context, *status)?;
+                        *status
+                    }
+                    None => {
+                        schedule_retry(our, state, context)?;
+                        DeliveryStatus::Pending
+                    }
+                };
+                results.push(BatchItemResult { id, status });
+            }
+
+            Ok(ChatResponse::BatchResult { results })
+        }
+        ChatRequest::ReceiveBatch { ref sends } => {
+            if source.node == our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "use SendBatch to send a batch",
+                ));
+            }
+
+            // Each message goes through the same checks as if it had come on its own
+            let mut results = vec![];
+            for send in sends {
+                let ChatRequest::Send {
+                    target,
+                    id: Some(id),
+                    ..
+                } = send
+                else {
+                    continue;
+                };
+                if target != &our.node {
+                    continue;
+                }
+                let response =
+                    handle_chat_request(our, state, source, &serde_json::to_vec(send)?, false)?;
+                results.push(BatchItemResult {
+                    id: id.clone(),
+                    status: match response {
+                        ChatResponse::Error { .. } => DeliveryStatus::Failed,
+                        _ => DeliveryStatus::Delivered,
+                    },
+                });
+            }
+
+            Ok(ChatResponse::BatchResult { results })
+        }
+        ChatRequest::Resend { from_seq, to_seq } => {
+            // Replay only our own messages from our chat with whoever asked
+            let replays: Vec<(MessageId, ChatRequest)> = state
+                .message_archive
+                .get(&source.node)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .filter(|message| message.author == our.node && !message.deleted)
+                .filter(|message| {
+                    message
+                        .seq
+                        .is_some_and(|seq| (from_seq..=to_seq).contains(&seq))
+                })
+                .map(|message| (message.id.clone(), resend_request(&source.node, message)))
+                .collect();
+
+            for (message_id, replay) in replays {
+                send_tracked_chat_request(
+                    state,
+                    &source.node,
+                    &replay,
+                    &DeliveryContext {
+                        chat: source.node.clone(),
+                        message_id,
+                        attempts: 0,
+                    },
+                )?;
+            }
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::SendToRoom {
+            ref room,
+            ref message,
+            timestamp,
+            ref id,
+        } => {
+            let is_local = source.node == our.node;
+            let author = source.node.clone();
+            let key = room_key(room);
+
+            // Only members may post, and we only take room messages from nodes we know are in it
+            let members = state.rooms.get(room).cloned().unwrap_or_default();
+            if !members.contains(&our.node) || !members.contains(&author) {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    format!("{} is not in room {}", author, room),
+                ));
+            }
+
+            let timestamp = match timestamp {
+                Some(timestamp) if !is_local => timestamp,
+                _ => now(),
+            };
+            let id = match id {
+                Some(id) if !is_local => id.clone(),
+                _ => state.new_message_id(&author),
+            };
+
+            // A retried delivery carries the same id, so don't store it twice
+            if find_message_mut(&mut state.message_archive, &key, &id).is_some() {
+                return Ok(ChatResponse::Ack);
+            }
+
+            if is_local {
+                // One request per member; an offline member just misses the message
+                let fanned_out = ChatRequest::SendToRoom {
+                    room: room.clone(),
+                    message: message.clone(),
+                    timestamp: Some(timestamp),
+                    id: Some(id.clone()),
+                };
+                for member in members.iter().filter(|member| *member != &our.node) {
+                    if let Err(error) = notify_chat_request(state, member, &fanned_out) {
+                        print_to_terminal(
+                            0,
+                            &format!(
+                                "chat: could not send to {} in {}: {:?}",
+                                member, room, error
+                            ),
+                        );
+                    }
+                }
+            }
+
+            let new_message = ChatMessage {
+                id,
+                author,
+                content: sanitize_content(message),
+                raw_content: state.settings.trusted_content.then(|| message.clone()),
+                timestamp,
+                mentions: parse_mentions(message),
+                ..ChatMessage::default()
+            };
+
+            store_message(our, state, &key, new_message, is_http)
+        }
+        ChatRequest::JoinRoom { ref room, ref via } => {
+            if state.groups.contains_key(room) {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    format!("{} is a group; members must be invited", room),
+                ));
+            }
+            // Another node joining a room we're in: record them and tell them who's here
+            if source.node != our.node {
+                let Some(members) = state.rooms.get_mut(room) else {
+                    return Ok(ChatResponse::error(
+                        StatusCode::NOT_FOUND,
+                        format!("not in room {}", room),
+                    ));
+                };
+                if !members.contains(&source.node) {
+                    members.push(source.node.clone());
+                }
+                let members = members.clone();
+                save_state(our, state)?;
+                return Ok(ChatResponse::Members {
+                    room: room.clone(),
+                    members,
+                });
+            }
+
+            let mut members = state.rooms.get(room).cloned().unwrap_or_default();
+            if let Some(via) = via.as_ref().filter(|via| *via != &our.node) {
+                match forward_chat_request(state, via, &chat_request)? {
+                    ChatResponse::Members { members: known, .. } => {
+                        for member in known {
+                            if !members.contains(&member) {
+                                members.push(member);
+                            }
+                        }
+                    }
+                    error @ ChatResponse::Error { .. } => return Ok(error),
+                    _ => {}
+                }
+            }
+            if !members.contains(&our.node) {
+                members.push(our.node.clone());
+            }
+
+            // Introduce ourselves to everyone else; `via` already knows
+            let announcement = ChatRequest::JoinRoom {
+                room: room.clone(),
+                via: None,
+            };
+            for member in members
+                .iter()
+                .filter(|member| *member != &our.node && Some(*member) != via.as_ref())
+            {
+                notify_chat_request(state, member, &announcement)?;
+            }
+
+            state.rooms.insert(room.clone(), members.clone());
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Members {
+                room: room.clone(),
+                members,
+            })
+        }
+        ChatRequest::LeaveRoom { ref room } => {
+            if source.node != our.node {
+                if let Some(members) = state.rooms.get_mut(room) {
+                    members.retain(|member| member != &source.node);
+                }
+                if let Some(roles) = state.group_roles.get_mut(room) {
+                    roles.remove(&source.node);
+                }
+            } else if let Some(members) = state.rooms.remove(room) {
+                state.group_roles.remove(room);
+                // The room's messages stay in the archive
+                for member in members.iter().filter(|member| *member != &our.node) {
+                    notify_chat_request(state, member, &chat_request)?;
+                }
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::CreateChat { ref target } => {
+            if source.node != our.node || target == &our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "chats are created with another node",
+                ));
+            }
+            if !state.message_archive.contains_key(target) {
+                state.message_archive.insert(target.clone(), vec![]);
+                state.recount_stats(target);
+                save_state(our, state)?;
+            }
+
+            Ok(ChatResponse::Chat(chat_summary(
+                state,
+                target,
+                &state.message_archive[target],
+            )))
+        }
+        ChatRequest::DeleteChat { ref target, notify } => {
+            // The counterparty deleted their copy; ours stays
+            if source.node != our.node {
+                push_to_channels(
+                    our,
+                    state,
+                    &ChatEvent::ChatDeleted {
+                        chat: source.node.clone(),
+                        by: source.node.clone(),
+                    },
+                )?;
+                return Ok(ChatResponse::Ack);
+            }
+
+            if !state.delete_chat(target) {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no chat with {}", target),
+                ));
+            }
+            save_state(our, state)?;
+
+            if notify {
+                notify_chat_request(state, target, &chat_request)?;
+            }
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::ChatDeleted {
+                    chat: target.clone(),
+                    by: our.node.clone(),
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::CloseChat { ref target } | ChatRequest::ReopenChat { ref target } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can close our chats",
+                ));
+            }
+            let closed = matches!(chat_request, ChatRequest::CloseChat { .. });
+            if closed {
+                state.closed.insert(target.clone());
+            } else {
+                state.closed.remove(target);
+            }
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::ChatClosed {
+                    chat: target.clone(),
+                    closed,
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::ArchiveChat { ref target } | ChatRequest::UnarchiveChat { ref target } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can archive our chats",
+                ));
+            }
+            if !state.message_archive.contains_key(target) {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no chat with {}", target),
+                ));
+            }
+            match chat_request {
+                ChatRequest::ArchiveChat { .. } => state.archived.insert(target.clone()),
+                _ => state.archived.remove(target),
+            };
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Conversations => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can list our chats",
+                ));
+            }
+            Ok(conversations(state))
+        }
+        ChatRequest::Broadcast { ref message } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can broadcast from this node",
+                ));
+            }
+            let mut targets: Vec<String> = state
+                .message_archive
+                .keys()
+                .filter(|chat| !chat.starts_with('#') && *chat != &our.node)
+                .cloned()
+                .collect();
+            targets.sort();
+
+            // Each copy is an ordinary `Send`, so it's stored, pushed to the UI and retried
+            // like any other; one chat failing doesn't stop the rest
+            let mut sent = vec![];
+            let mut failed = HashMap::new();
+            for target in targets {
+                let send = ChatRequest::Send {
+                    target: target.clone(),
+                    message: message.clone(),
+                    timestamp: None,
+                    id: None,
+                    reply_to: None,
+                    format: MessageFormat::default(),
+                    expires_in_seconds: None,
+                    forwarded_from: None,
+                    kind: MessageKind::Text,
+                    seq: None,
+                    client_key: None,
+                    sealed: None,
+                    metadata: HashMap::new(),
+                    signature: None,
+                };
+                // Not as HTTP, so the UI gets a `NewMessage` for each chat
+                match handle_chat_request(our, state, our, &serde_json::to_vec(&send)?, false) {
+                    Ok(ChatResponse::Error { message, .. }) => {
+                        failed.insert(target, message);
+                    }
+                    Ok(_) => sent.push(target),
+                    Err(e) => {
+                        failed.insert(target, e.to_string());
+                    }
+                }
+            }
+            Ok(ChatResponse::BroadcastResult { sent, failed })
+        }
+        ChatRequest::ListArchived => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can list our archived chats",
+                ));
+            }
+            let mut chats: Vec<String> = state.archived.iter().cloned().collect();
+            chats.sort();
+            Ok(ChatResponse::Archived { chats })
+        }
+        ChatRequest::CreateGroup {
+            ref name,
+            ref members,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "groups are created with an invite",
+                ));
+            }
+            let group = state.new_message_id(&our.node);
+            let mut members = members.clone();
+            members.retain(|member| member != &our.node);
+            members.sort();
+            members.dedup();
+            members.insert(0, our.node.clone());
+
+            let roles = HashMap::from([(our.node.clone(), GroupRole::Owner)]);
+
+            state.rooms.insert(group.clone(), members.clone());
+            state.groups.insert(group.clone(), sanitize_content(name));
+            state.group_roles.insert(group.clone(), roles.clone());
+            save_state(our, state)?;
+
+            // A member that's offline now won't see the group until invited again
+            let invite = ChatRequest::GroupInvite {
+                group: group.clone(),
+                name: name.clone(),
+                members: members.clone(),
+                roles,
+            };
+            for member in members.iter().filter(|member| *member != &our.node) {
+                notify_chat_request(state, member, &invite)?;
+            }
+
+            Ok(group_info(state, &group))
+        }
+        ChatRequest::GroupInvite {
+            ref group,
+            ref name,
+            ref members,
+            ref roles,
+        } => {
+            // Only a member can invite, and never into a room we're already in
+            if !members.contains(&source.node) || !members.contains(&our.node) {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "invites must come from a member",
+                ));
+            }
+            if state.rooms.contains_key(group) {
+                return Ok(ChatResponse::error(
+                    StatusCode::CONFLICT,
+                    format!("already in {}", group),
+                ));
+            }
+            state.rooms.insert(group.clone(), members.clone());
+            state.groups.insert(group.clone(), sanitize_content(name));
+            state.group_roles.insert(group.clone(), roles.clone());
+            save_state(our, state)?;
+
+            push_to_channels(our, state, &group_info(state, group))?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::AddMember { ref group, .. }
+        | ChatRequest::RemoveMember { ref group, .. }
+        | ChatRequest::RenameGroup { ref group, .. }
+        | ChatRequest::PromoteMember { ref group, .. }
+        | ChatRequest::DemoteMember { ref group, .. } => {
+            // Whoever asks, us or another member, must be an admin by our own records
+            let before = state.rooms.get(group).cloned().unwrap_or_default();
+            if let Err((code, reason)) = change_group(state, &source.node, &chat_request) {
+                return Ok(ChatResponse::error(code, reason));
+            }
+
+            // Removed from the group ourselves; its messages stay in the archive
+            if let ChatRequest::RemoveMember { ref member, .. } = chat_request {
+                if member == &our.node {
+                    state.rooms.remove(group);
+                    state.groups.remove(group);
+                    state.group_roles.remove(group);
+                }
+            }
+            save_state(our, state)?;
+
+            if source.node == our.node {
+                // Everyone who was or now is in the group hears of it. Someone just added
+                // gets the whole group instead, as if invited to it.
+                let after = state.rooms.get(group).cloned().unwrap_or_default();
+                let invite = ChatRequest::GroupInvite {
+                    group: group.clone(),
+                    name: unsanitize(&state.groups.get(group).cloned().unwrap_or_default()),
+                    members: after.clone(),
+                    roles: state.group_roles.get(group).cloned().unwrap_or_default(),
+                };
+                for (member, was_member) in group_change_recipients(&our.node, &before, &after) {
+                    let request = match was_member {
+                        true => &chat_request,
+                        false => &invite,
+                    };
+                    if let Err(error) = notify_chat_request(state, member, request) {
+                        print_to_terminal(
+                            0,
+                            &format!(
+                                "chat: could not tell {} of a change to {}: {:?}",
+                                member, group, error
+                            ),
+                        );
+                    }
+                }
+            }
+
+            push_to_channels(our, state, &group_info(state, group))?;
+
+            Ok(group_info(state, group))
+        }
+        ChatRequest::SaveDraft {
+            ref chat,
+            ref content,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "drafts are local to this node",
+                ));
+            }
+            if content.is_empty() {
+                state.drafts.remove(chat);
+            } else {
+                state.drafts.insert(chat.clone(), content.clone());
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Summaries => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can list our chats",
+                ));
+            }
+            Ok(summaries(state))
+        }
+        ChatRequest::GetDrafts => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "drafts are local to this node",
+                ));
+            }
+            Ok(ChatResponse::Drafts {
+                drafts: state.drafts.clone(),
+            })
+        }
+        ChatRequest::Configure {
+            send_timeout_secs,
+            max_message_length,
+            max_edit_history,
+            max_attachment_size,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can configure this node",
+                ));
+            }
+            let current = &state.settings;
+            let settings = Settings {
+                send_timeout_secs: send_timeout_secs.or(current.send_timeout_secs),
+                max_message_length: max_message_length.or(current.max_message_length),
+                max_edit_history: max_edit_history.or(current.max_edit_history),
+                max_attachment_size: max_attachment_size.or(current.max_attachment_size),
+                ..current.clone()
+            };
+            match apply_settings(our, state, settings)? {
+                ChatResponse::Settings(_) => Ok(ChatResponse::Ack),
+                error => Ok(error),
+            }
+        }
+        ChatRequest::Ping { ref target } => {
+            if target == &our.node {
+                return Ok(ChatResponse::Pong);
+            }
+
+            let response = forward_chat_request(state, target, &chat_request)?;
+            let online = matches!(response, ChatResponse::Pong);
+            set_presence(our, state, target, online);
+
+            Ok(match online {
+                true => ChatResponse::Pong,
+                false => ChatResponse::error(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!("{} did not answer", target),
+                ),
+            })
+        }
+        ChatRequest::LastSeen { ref target } => {
+            // Only contacts get to know, and only while we share it
+            if target == &our.node {
+                let shared = state.settings.share_last_seen
+                    && state.contacts.contains(&source.node)
+                    && state.last_active != 0;
+                return Ok(ChatResponse::LastSeen {
+                    last_seen: match shared {
+                        true => LastSeen::At(state.last_active),
+                        false => LastSeen::Hidden,
+                    },
+                });
+            }
+
+            let response = forward_chat_request(state, target, &chat_request)?;
+            if let ChatResponse::LastSeen { last_seen } = response {
+                state.their_last_seen.insert(target.clone(), last_seen);
+                save_state(our, state)?;
+            }
+            Ok(response)
+        }
+        ChatRequest::CreatePoll {
+            ref target,
+            ref question,
+            ref options,
+            timestamp,
+            ref id,
+        } => {
+            let (counterparty, author) = if target == &our.node {
+                (&source.node, source.node.clone())
+            } else {
+                (target, our.node.clone())
+            };
+            if options.len() < 2 {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "polls need at least two options",
+                ));
+            }
+
+            let id = match id {
+                Some(id) if target == &our.node => id.clone(),
+                _ => state.new_message_id(&author),
+            };
+            if find_message_mut(&mut state.message_archive, counterparty, &id).is_some() {
+                return Ok(ChatResponse::Ack);
+            }
+            let timestamp = match timestamp {
+                Some(timestamp) if target == &our.node => timestamp,
+                _ => now(),
+            };
+
+            // The poll only exists once the counterparty has it too
+            if target != &our.node {
+                if let error @ ChatResponse::Error { .. } = forward_chat_request(
+                    state,
+                    target,
+                    &ChatRequest::CreatePoll {
+                        target: target.clone(),
+                        question: question.clone(),
+                        options: options.clone(),
+                        timestamp: Some(timestamp),
+                        id: Some(id.clone()),
+                    },
+                )? {
+                    return Ok(error);
+                }
+            }
+
+            let new_message = ChatMessage {
+                id,
+                author,
+                content: sanitize_content(question),
+                timestamp,
+                kind: MessageKind::Poll,
+                poll: Some(Poll {
+                    options: options
+                        .iter()
+                        .map(|text| PollOption {
+                            text: sanitize_content(text),
+                            votes: 0,
+                            voters: vec![],
+                        })
+                        .collect(),
+                    closed: false,
+                }),
+                ..ChatMessage::default()
+            };
+
+            store_message(our, state, counterparty, new_message, is_http)
+        }
+        ChatRequest::SendLocation {
+            ref target,
+            lat,
+            lon,
+            ref label,
+            timestamp,
+            ref id,
+        } => {
+            if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("{}, {} is not a valid location", lat, lon),
+                ));
+            }
+            let (counterparty, author) = if target == &our.node {
+                (&source.node, source.node.clone())
+            } else {
+                (target, our.node.clone())
+            };
+
+            let id = match id {
+                Some(id) if target == &our.node => id.clone(),
+                _ => state.new_message_id(&author),
+            };
+            if find_message_mut(&mut state.message_archive, counterparty, &id).is_some() {
+                return Ok(ChatResponse::Ack);
+            }
+            let timestamp = match timestamp {
+                Some(timestamp) if target == &our.node => timestamp,
+                _ => now(),
+            };
+
+            if target != &our.node {
+                if let error @ ChatResponse::Error { .. } = forward_chat_request(
+                    state,
+                    target,
+                    &ChatRequest::SendLocation {
+                        target: target.clone(),
+                        lat,
+                        lon,
+                        label: label.clone(),
+                        timestamp: Some(timestamp),
+                        id: Some(id.clone()),
+                    },
+                )? {
+                    return Ok(error);
+                }
+            }
+
+            // The label doubles as the content, so search and previews find it
+            let label = label.as_deref().map(sanitize_content);
+            let new_message = ChatMessage {
+                id,
+                author,
+                content: label.clone().unwrap_or_default(),
+                timestamp,
+                kind: MessageKind::Location,
+                location: Some(Location { lat, lon, label }),
+                ..ChatMessage::default()
+            };
+
+            store_message(our, state, counterparty, new_message, is_http)
+        }
+        ChatRequest::Vote {
+            ref chat,
+            ref poll_id,
+            option_index,
+        } => {
+            let (counterparty, voter) = if chat == &our.node {
+                (&source.node, &source.node)
+            } else {
+                (chat, &our.node)
+            };
+
+            let options = match open_poll(state, counterparty, poll_id) {
+                Ok(message) => message.poll.as_ref().map_or(0, |poll| poll.options.len()),
+                Err(error) => return Ok(*error),
+            };
+            if option_index >= options {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("poll {} has no option {}", poll_id, option_index),
+                ));
+            }
+
+            // Both sides keep the same tally
+            if chat != &our.node {
+                if let error @ ChatResponse::Error { .. } =
+                    forward_chat_request(state, chat, &chat_request)?
+                {
+                    return Ok(error);
+                }
+            }
+
+            let Ok(message) = open_poll(state, counterparty, poll_id) else {
+                return Ok(ChatResponse::Ack);
+            };
+            let Some(poll) = message.poll.as_mut() else {
+                return Ok(ChatResponse::Ack);
+            };
+            poll.vote(voter, option_index);
+            let poll = poll.clone();
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::PollUpdated {
+                    chat: counterparty.clone(),
+                    poll_id: poll_id.clone(),
+                    poll,
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::ClosePoll {
+            ref chat,
+            ref poll_id,
+        } => {
+            let (counterparty, requester) = if chat == &our.node {
+                (&source.node, &source.node)
+            } else {
+                (chat, &our.node)
+            };
+
+            match open_poll(state, counterparty, poll_id) {
+                Ok(message) if &message.author != requester => {
+                    return Ok(ChatResponse::error(
+                        StatusCode::FORBIDDEN,
+                        "cannot close another node's poll",
+                    ));
+                }
+                Ok(_) => {}
+                Err(error) => return Ok(*error),
+            }
+
+            if chat != &our.node {
+                if let error @ ChatResponse::Error { .. } =
+                    forward_chat_request(state, chat, &chat_request)?
+                {
+                    return Ok(error);
+                }
+            }
+
+            let Some(poll) = find_message_mut(&mut state.message_archive, counterparty, poll_id)
+                .and_then(|message| message.poll.as_mut())
+            else {
+                return Ok(ChatResponse::Ack);
+            };
+            poll.closed = true;
+            let poll = poll.clone();
+            save_state(our, state)?;
+
+            push_to_channels(
+                our,
+                state,
+                &ChatEvent::PollUpdated {
+                    chat: counterparty.clone(),
+                    poll_id: poll_id.clone(),
+                    poll,
+                },
+            )?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::AddFilterWord { ref word } | ChatRequest::RemoveFilterWord { ref word } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can change the content filter",
+                ));
+            }
+            let word = word.trim().to_lowercase();
+            if word.is_empty() || !word.chars().all(char::is_alphanumeric) {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "filtered words must be letters and digits only",
+                ));
+            }
+            let words = &mut state.filter.words;
+            match (chat_request, words.binary_search(&word)) {
+                (ChatRequest::AddFilterWord { .. }, Err(index)) => words.insert(index, word),
+                (ChatRequest::RemoveFilterWord { .. }, Ok(index)) => {
+                    words.remove(index);
+                }
+                _ => {}
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::SetFilterAction { action } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can change the content filter",
+                ));
+            }
+            state.filter.action = action;
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Stats => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can see our chat stats",
+                ));
+            }
+            Ok(ChatResponse::Stats {
+                stats: state.stats.clone(),
+            })
+        }
+        ChatRequest::SetRetention { retention } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can change retention",
+                ));
+            }
+            let settings = Settings {
+                retention,
+                ..state.settings.clone()
+            };
+            apply_settings(our, state, settings)
+        }
+        ChatRequest::UpdateSettings { ref settings } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can configure this node",
+                ));
+            }
+            apply_settings(our, state, settings.clone())
+        }
+        ChatRequest::Block { ref node } | ChatRequest::Unblock { ref node } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can block nodes",
+                ));
+            }
+            if !is_valid_node_name(node) {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid node name {:?}", node),
+                ));
+            }
+            match chat_request {
+                ChatRequest::Block { .. } => state.blocked.insert(node.clone()),
+                _ => state.blocked.remove(node),
+            };
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Mute {
+            ref chat,
+            duration_secs,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "mutes are local to this node",
+                ));
+            }
+            state
+                .muted
+                .insert(chat.clone(), duration_secs.map(|secs| now() + secs));
+            let had_unread = state.unread.remove(chat).is_some();
+            save_state(our, state)?;
+
+            if had_unread {
+                push_to_channels(
+                    our,
+                    state,
+                    &ChatEvent::UnreadChanged {
+                        chat: chat.clone(),
+                        count: 0,
+                    },
+                )?;
+            }
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Unmute { ref chat } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "mutes are local to this node",
+                ));
+            }
+            state.muted.remove(chat);
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::AddContact {
+            ref node,
+            ref nickname,
+        } => {
+            if source.node != our.node || node == &our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "contacts are other nodes, kept on this one",
+                ));
+            }
+            state.contacts.insert(node.clone());
+            if let Some(nickname) = nickname.as_deref().map(str::trim) {
+                if !nickname.is_empty() {
+                    state.aliases.insert(node.clone(), nickname.to_string());
+                }
+            }
+            // So the contact shows up in the chat list before anything is said
+            if !state.message_archive.contains_key(node) {
+                state.message_archive.insert(node.clone(), vec![]);
+                state.recount_stats(node);
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::RemoveContact { ref node } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "contacts are local to this node",
+                ));
+            }
+            if !state.contacts.remove(node) {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("{} is not a contact", node),
+                ));
+            }
+            state.aliases.remove(node);
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::ListContacts => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "contacts are local to this node",
+                ));
+            }
+            let mut contacts: Vec<Contact> = state
+                .contacts
+                .iter()
+                .map(|node| Contact {
+                    node: node.clone(),
+                    nickname: state.aliases.get(node).cloned(),
+                })
+                .collect();
+            contacts.sort_by(|a, b| a.node.cmp(&b.node));
+            Ok(ChatResponse::Contacts { contacts })
+        }
+        ChatRequest::SetChatSettings { ref chat, settings } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "chat settings are local to this node",
+                ));
+            }
+            if !state.message_archive.contains_key(chat) {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no chat with {}", chat),
+                ));
+            }
+            // Back to the defaults leaves nothing to store
+            if settings == ChatSettings::default() {
+                state.chat_settings.remove(chat);
+            } else {
+                state.chat_settings.insert(chat.clone(), settings);
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::Rename { ref old, ref new } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can rename our chats",
+                ));
+            }
+            if !state.message_archive.contains_key(old) || old.starts_with('#') {
+                return Ok(ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no chat with {}", old),
+                ));
+            }
+            if !is_valid_node_name(new) || new == &our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid node name {:?}", new),
+                ));
+            }
+            if old != new {
+                state.rename_chat(old, new);
+                save_state(our, state)?;
+            }
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::SetAlias {
+            ref node,
+            ref alias,
+        } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "aliases are local to this node",
+                ));
+            }
+            if !is_valid_node_name(node) {
+                return Ok(ChatResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid node name {:?}", node),
+                ));
+            }
+            let alias = alias.trim();
+            if alias.is_empty() {
+                state.aliases.remove(node);
+            } else {
+                state.aliases.insert(node.clone(), alias.to_string());
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Ack)
+        }
+        ChatRequest::AcceptChat { ref node } | ChatRequest::RejectChat { ref node } => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can answer our message requests",
+                ));
+            }
+            let held = state.message_requests.remove(node).unwrap_or_default();
+            if let ChatRequest::RejectChat { .. } = chat_request {
+                state.ignored.insert(node.clone());
+                save_state(our, state)?;
+                return Ok(ChatResponse::Ack);
+            }
+
+            // The chat exists from now on, so storing the held messages doesn't hold them again
+            state.ignored.remove(node);
+            state.message_archive.entry(node.clone()).or_default();
+            for message in held {
+                store_message(our, state, node, message, false)?;
+            }
+            save_state(our, state)?;
+
+            Ok(ChatResponse::Chat(chat_summary(
+                state,
+                node,
+                &state.message_archive[node],
+            )))
+        }
+        ChatRequest::ListMessageRequests => {
+            if source.node != our.node {
+                return Ok(ChatResponse::error(
+                    StatusCode::FORBIDDEN,
+                    "only we can list our message requests",
+                ));
+            }
+            Ok(ChatResponse::MessageRequests {
+                requests: state.message_requests.clone(),
+            })
+        }
+        ChatRequest::History => {
+            purge_expired(our, state)?;
+            let mut history = history(state);
+            // Drafts and aliases never leave this node
+            if let ChatResponse::History {
+                ref mut drafts,
+                ref mut aliases,
+                ..
+            } = history
+            {
+                if source.node != our.node {
+                    drafts.clear();
+                    aliases.clear();
+                }
+            }
+            Ok(history)
+        }
+        // Only means something on the WebSocket it arrives on, which handles it itself
+        ChatRequest::Subscribe { .. } => Ok(ChatResponse::error(
+            StatusCode::BAD_REQUEST,
+            "subscribe over a WebSocket",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROCESS: &str = "testing:testing:template.uq";
+
+    fn address(node: &str) -> Address {
+        Address {
+            node: node.to_string(),
+            process: ProcessId::from_str(PROCESS).unwrap(),
+        }
+    }
+
+    fn state() -> State {
+        State {
+            process: PROCESS.to_string(),
+            ..State::default()
+        }
+    }
+
+    fn edit(target: &str) -> ChatRequest {
+        ChatRequest::Edit {
+            target: target.to_string(),
+            message_id: "our.uq:1".to_string(),
+            new_content: "changed".to_string(),
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn remote_requests_must_target_us() {
+        let our = address("our.uq");
+        let bob = address("bob.uq");
+        let state = state();
+
+        assert!(impersonation(&our, &state, &bob, &edit("our.uq")).is_none());
+        assert!(impersonation(&our, &state, &bob, &edit("carol.uq")).is_some());
+        for request in [
+            ChatRequest::Delete {
+                target: "carol.uq".to_string(),
+                message_id: Some("our.uq:1".to_string()),
+                index: None,
+            },
+            ChatRequest::Ping {
+                target: "carol.uq".to_string(),
+            },
+            ChatRequest::Vote {
+                chat: "carol.uq".to_string(),
+                poll_id: "our.uq:2".to_string(),
+                option_index: 0,
+            },
+        ] {
+            assert!(impersonation(&our, &state, &bob, &request).is_some());
+        }
+    }
+
+    #[test]
+    fn we_may_target_anyone() {
+        let our = address("our.uq");
+        assert!(impersonation(&our, &state(), &our, &edit("carol.uq")).is_none());
+    }
+
+    #[test]
+    fn group_invites_name_other_members() {
+        let our = address("our.uq");
+        let invite = ChatRequest::GroupInvite {
+            group: "bob.uq:3".to_string(),
+            name: "friends".to_string(),
+            members: vec![
+                "bob.uq".to_string(),
+                "carol.uq".to_string(),
+                "our.uq".to_string(),
+            ],
+            roles: HashMap::new(),
+        };
+        assert!(impersonation(&our, &state(), &address("bob.uq"), &invite).is_none());
+    }
+
+    #[test]
+    fn only_this_app_may_send_chat_requests() {
+        let our = address("our.uq");
+        let other = Address {
+            node: "bob.uq".to_string(),
+            process: ProcessId::from_str("other:other:template.uq").unwrap(),
+        };
+        assert!(impersonation(&our, &state(), &other, &edit("our.uq")).is_some());
+    }
+
+    #[test]
+    fn filter_covers_edits_and_polls() {
+        let filter = ContentFilter {
+            words: vec!["darn".to_string()],
+            action: FilterAction::Censor,
+        };
+        let mut request = edit("bob.uq");
+        if let ChatRequest::Edit {
+            ref mut new_content,
+            ..
+        } = request
+        {
+            *new_content = "well darn".to_string();
+        }
+        assert_eq!(
+            apply_filter(&filter, &mut request).ok(),
+            Some(Some(FilterAction::Censor))
+        );
+        assert_eq!(request.contents(), vec!["well ****"]);
+
+        let mut poll = ChatRequest::CreatePoll {
+            target: "bob.uq".to_string(),
+            question: "which?".to_string(),
+            options: vec!["this".to_string(), "Darn that".to_string()],
+            timestamp: None,
+            id: None,
+        };
+        apply_filter(&filter, &mut poll).unwrap();
+        assert_eq!(poll.contents(), vec!["which?", "this", "**** that"]);
+
+        let reject = ContentFilter {
+            action: FilterAction::Reject,
+            ..filter
+        };
+        assert!(apply_filter(&reject, &mut edit("bob.uq")).is_ok());
+        let mut room = ChatRequest::SendToRoom {
+            room: "lobby".to_string(),
+            message: "darn".to_string(),
+            timestamp: None,
+            id: None,
+        };
+        assert!(apply_filter(&reject, &mut room).is_err());
+    }
+
+    #[test]
+    fn group_changes_reach_each_member_once() {
+        let before = vec![
+            "us.uq".to_string(),
+            "bob.uq".to_string(),
+            "carol.uq".to_string(),
+        ];
+        let after = vec![
+            "us.uq".to_string(),
+            "bob.uq".to_string(),
+            "dave.uq".to_string(),
+        ];
+        let bob = "bob.uq".to_string();
+        let carol = "carol.uq".to_string();
+        let dave = "dave.uq".to_string();
+        assert_eq!(
+            group_change_recipients("us.uq", &before, &after),
+            vec![(&bob, true), (&dave, false), (&carol, true)],
+        );
+    }
+
+    #[test]
+    fn edits_to_encrypted_chats_are_sealed() {
+        let mut state = state();
+        let keys = ChatKeys {
+            current: 1,
+            keys: HashMap::from([(1, [7; 32])]),
+        };
+        state.chat_keys.insert(
+            "bob.uq".to_string(),
+            ChatKeys {
+                current: 1,
+                keys: keys.keys.clone(),
+            },
+        );
+
+        let ChatRequest::Edit {
+            new_content,
+            sealed: Some(sealed),
+            ..
+        } = seal_send(&state, edit("bob.uq")).unwrap()
+        else {
+            panic!("edit was not sealed");
+        };
+        assert!(new_content.is_empty());
+        assert_eq!(open_sealed(&keys, &sealed).as_deref(), Some("changed"));
+
+        let ChatRequest::Edit {
+            new_content,
+            sealed: None,
+            ..
+        } = seal_send(&state, edit("carol.uq")).unwrap()
+        else {
+            panic!("edit was sealed without a key");
+        };
+        assert_eq!(new_content, "changed");
+    }
+
+    #[test]
+    fn metadata_is_sanitized_and_restored() {
+        let metadata = HashMap::from([(
+            "<b>".to_string(),
+            serde_json::json!({"html": ["<img onerror=x>", 1], "ok": true}),
+        )]);
+        let sanitized = map_metadata(&metadata, &sanitize_content);
+        assert_eq!(
+            sanitized.get("&lt;b&gt;"),
+            Some(&serde_json::json!({"html": ["&lt;img onerror=x&gt;", 1], "ok": true}))
+        );
+        assert_eq!(map_metadata(&sanitized, &unsanitize), metadata);
+    }
+
+    #[test]
+    fn mark_read_takes_just_a_chat() {
+        let request: ChatRequest =
+            serde_json::from_str(r#"{"MarkRead": {"chat": "bob.uq"}}"#).unwrap();
+        assert!(matches!(
+            request,
+            ChatRequest::MarkRead {
+                ref target,
+                up_to_message_id: None,
+            } if target == "bob.uq"
+        ));
+    }
+
+    #[test]
+    fn each_ack_is_for_its_own_message() {
+        let context = |message_id: &str| DeliveryContext {
+            chat: "bob.uq".to_string(),
+            message_id: message_id.to_string(),
+            attempts: 0,
+        };
+        let received = |request_id: &str| {
+            serde_json::to_vec(&ChatResponse::Received {
+                request_id: request_id.to_string(),
+            })
+            .unwrap()
+        };
+
+        // Both are in flight; the second one's ack comes back first
+        let (second, status) = delivery_outcome(context("our.uq:2"), &received("our.uq:2"));
+        assert_eq!(second.message_id, "our.uq:2");
+        assert_eq!(status, DeliveryStatus::Delivered);
+        let (first, status) = delivery_outcome(context("our.uq:1"), &received("our.uq:1"));
+        assert_eq!(first.message_id, "our.uq:1");
+        assert_eq!(status, DeliveryStatus::Delivered);
+
+        let rejected =
+            serde_json::to_vec(&ChatResponse::error(StatusCode::FORBIDDEN, "blocked")).unwrap();
+        let (first, status) = delivery_outcome(context("our.uq:1"), &rejected);
+        assert_eq!(first.message_id, "our.uq:1");
+        assert_eq!(status, DeliveryStatus::Failed);
+    }
+
+    #[test]
+    fn nested_tags_are_escaped() {
+        assert_eq!(
+            sanitize_content("<div><script>alert(1)</script></div>"),
+            "&lt;div&gt;&lt;script&gt;alert(1)&lt;/script&gt;&lt;/div&gt;"
+        );
+        // Half a tag inside another can't reassemble into one
+        assert_eq!(
+            sanitize_content("<scr<script>ipt>"),
+            "&lt;scr&lt;script&gt;ipt&gt;"
+        );
+    }
+
+    #[test]
+    fn event_handler_attributes_are_escaped() {
+        let sanitized = sanitize_content(r#"<img src=x onerror="alert('hi')">"#);
+        assert_eq!(
+            sanitized,
+            "&lt;img src=x onerror=&quot;alert(&#39;hi&#39;)&quot;&gt;"
+        );
+        assert!(!sanitized.contains(['<', '>', '"', '\'']));
+    }
+
+    #[test]
+    fn long_content_is_escaped_whole() {
+        let content = "<b>&</b>".repeat(100_000);
+        let sanitized = sanitize_content(&content);
+        assert_eq!(sanitized, "&lt;b&gt;&amp;&lt;/b&gt;".repeat(100_000));
+        assert_eq!(unsanitize(&sanitized), content);
+    }
+
+    #[test]
+    fn retries_back_off_then_give_up() {
+        assert_eq!(retry_backoff(1), Some(RETRY_BACKOFF_SECS));
+        assert_eq!(retry_backoff(2), Some(RETRY_BACKOFF_SECS * 2));
+        assert_eq!(
+            retry_backoff(MAX_DELIVERY_ATTEMPTS - 1),
+            Some(RETRY_BACKOFF_SECS << (MAX_DELIVERY_ATTEMPTS - 2))
+        );
+        assert_eq!(retry_backoff(MAX_DELIVERY_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn sends_to_unreachable_nodes_are_retried_then_failed() {
+        let mut state = state();
+        state.message_archive.insert(
+            "nobody-here.uq".to_string(),
+            vec![ChatMessage {
+                id: "our.uq:1".to_string(),
+                author: "our.uq".to_string(),
+                status: DeliveryStatus::Pending,
+                ..ChatMessage::default()
+            }],
+        );
+        let status = |state: &State| state.message_archive["nobody-here.uq"][0].status;
+
+        // What the send error for each attempt carries back
+        let mut context = DeliveryContext {
+            chat: "nobody-here.uq".to_string(),
+            message_id: "our.uq:1".to_string(),
+            attempts: 0,
+        };
+        for attempts in 1..MAX_DELIVERY_ATTEMPTS {
+            assert!(queue_retry(&mut state, context).is_ok());
+            let pending = state.pending.pop().unwrap();
+            assert_eq!(pending.attempts, attempts);
+            assert_eq!(status(&state), DeliveryStatus::Pending);
+            context = DeliveryContext {
+                chat: pending.chat,
+                message_id: pending.message_id,
+                attempts: pending.attempts,
+            };
+        }
+
+        // Out of attempts, it's given up on rather than queued forever
+        let Err(context) = queue_retry(&mut state, context) else {
+            panic!("queued past the last attempt");
+        };
+        assert!(state.pending.is_empty());
+        assert!(set_delivery_status(
+            &mut state,
+            &context.chat,
+            &context.message_id,
+            DeliveryStatus::Failed
+        ));
+        assert_eq!(status(&state), DeliveryStatus::Failed);
+    }
+
+    #[test]
+    fn node_names_are_checked_before_sending() {
+        assert!(is_valid_node_name("nobody-here.uq"));
+        assert!(is_valid_node_name("bob_2.uq"));
+        for name in ["", ".uq", "bob.", "bob..uq", "bob uq", "bob/uq", "<bob>.uq"] {
+            assert!(!is_valid_node_name(name), "{:?} passed", name);
+        }
+    }
+
+    #[test]
+    fn forwards_go_to_our_own_process_id() {
+        let mut state = state();
+        state.process = "chat:chat:someone.uq".to_string();
+        let address = chat_address(&state, "bob.uq").unwrap();
+        assert_eq!(address.node, "bob.uq");
+        assert_eq!(address.process.to_string(), "chat:chat:someone.uq");
+    }
+
+    #[test]
+    fn each_websocket_gets_a_new_message_once() {
+        let mut state = state();
+        state.channels.insert(1, None);
+        state.channels.insert(2, Some("bob.uq".to_string()));
+        state.channels.insert(3, Some("carol.uq".to_string()));
+        let events = [
+            ChatEvent::NewMessage(Box::new(NewMessage {
+                chat: "bob.uq".to_string(),
+                chat_kind: ChatKind::of("bob.uq"),
+                muted: false,
+                should_notify: true,
+                display_name: None,
+                message: ChatMessage::default(),
+            })),
+            ChatEvent::UnreadChanged {
+                chat: "bob.uq".to_string(),
+                count: 1,
+            },
+        ];
+
+        let pushes = route_events(&state, "bob.uq", &events);
+        let new_messages = |channel_id| {
+            pushes
+                .iter()
+                .filter(|(id, event)| {
+                    *id == channel_id && matches!(event, ChatEvent::NewMessage(_))
+                })
+                .count()
+        };
+        assert_eq!(new_messages(1), 1);
+        assert_eq!(new_messages(2), 1);
+        assert_eq!(new_messages(3), 0);
+        // The unread count is for everyone
+        assert_eq!(pushes.len(), 2 + 3);
+    }
+
+    /// What our node answers a request from `source`
+    fn respond(source: &Address, state: &mut State, request: &ChatRequest) -> ChatResponse {
+        let ipc = serde_json::to_vec(request).unwrap();
+        handle_chat_request(&address("our.uq"), state, source, &ipc, false).unwrap()
+    }
+
+    fn is_forbidden(response: &ChatResponse) -> bool {
+        matches!(response, ChatResponse::Error { code: 403, .. })
+    }
+
+    #[test]
+    fn other_nodes_cannot_list_our_chats() {
+        let mut state = state();
+        state.message_archive.insert(
+            "carol.uq".to_string(),
+            vec![ChatMessage {
+                id: "carol.uq:1".to_string(),
+                author: "carol.uq".to_string(),
+                content: "just between us".to_string(),
+                ..ChatMessage::default()
+            }],
+        );
+        let response = respond(&address("bob.uq"), &mut state, &ChatRequest::Summaries);
+        assert!(is_forbidden(&response), "{:?}", response);
+    }
+
+    #[test]
+    fn other_nodes_cannot_search_our_chats() {
+        let mut state = state();
+        for counterparty in [None, Some("bob.uq".to_string())] {
+            let search = ChatRequest::Search {
+                query: "secret".to_string(),
+                counterparty,
+                limit: None,
+            };
+            let response = respond(&address("bob.uq"), &mut state, &search);
+            assert!(is_forbidden(&response), "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn blocked_nodes_cannot_tell() {
+        let mut state = state();
+        state.blocked.insert("bob.uq".to_string());
+        state.closed.insert("bob.uq".to_string());
+        let send: ChatRequest = serde_json::from_value(serde_json::json!({"Send": {
+            "target": "our.uq",
+            "message": "x".repeat(state.max_message_length() + 1),
+            "id": "bob.uq:1",
+            "timestamp": 10,
+            "signature": {"public_key": vec![0; 32], "signature": vec![0; 64]},
+        }}))
+        .unwrap();
+        assert!(matches!(
+            respond(&address("bob.uq"), &mut state, &send),
+            ChatResponse::Received { ref request_id } if request_id == "bob.uq:1"
+        ));
+        assert!(matches!(
+            respond(&address("bob.uq"), &mut state, &edit("our.uq")),
+            ChatResponse::Ack
+        ));
+        assert!(!state.verifying_keys.contains_key("bob.uq"));
+        assert!(!state.message_archive.contains_key("bob.uq"));
+    }
+
+    #[test]
+    fn repeated_deliveries_are_not_stored_again() {
+        let mut state = state();
+        let send = |id: &str| -> ChatRequest {
+            serde_json::from_value(serde_json::json!({"Send": {
+                "target": "our.uq",
+                "message": "hello",
+                "id": id,
+                "timestamp": 10,
+                "seq": 1,
+            }}))
+            .unwrap()
+        };
+        // The first delivery, as the handler leaves it. Storing again would need the VFS,
+        // which isn't there in tests, so a repeat has to be turned away before that.
+        state.message_archive.insert(
+            "bob.uq".to_string(),
+            vec![ChatMessage {
+                id: "bob.uq:1".to_string(),
+                author: "bob.uq".to_string(),
+                content: "hello".to_string(),
+                seq: Some(1),
+                ..ChatMessage::default()
+            }],
+        );
+        state.track_seq("bob.uq", 1);
+
+        assert!(matches!(
+            respond(&address("bob.uq"), &mut state, &send("bob.uq:1")),
+            ChatResponse::Received { ref request_id } if request_id == "bob.uq:1"
+        ));
+        assert_eq!(state.message_archive["bob.uq"].len(), 1);
+
+        // The same message under another id is still caught by its sequence number
+        assert!(matches!(
+            respond(&address("bob.uq"), &mut state, &send("bob.uq:2")),
+            ChatResponse::Ack
+        ));
+        assert_eq!(state.message_archive["bob.uq"].len(), 1);
+    }
+
+    #[test]
+    fn group_names_are_sanitized() {
+        let mut state = state();
+        state
+            .groups
+            .insert("bob.uq:1".to_string(), "Friends".to_string());
+        state.rooms.insert(
+            "bob.uq:1".to_string(),
+            vec!["bob.uq".to_string(), "our.uq".to_string()],
+        );
+        state.group_roles.insert(
+            "bob.uq:1".to_string(),
+            HashMap::from([("bob.uq".to_string(), GroupRole::Owner)]),
+        );
+        let rename = ChatRequest::RenameGroup {
+            group: "bob.uq:1".to_string(),
+            name: "<img src=x onerror=alert(1)>".to_string(),
+        };
+        assert!(change_group(&mut state, "bob.uq", &rename).is_ok());
+        assert_eq!(
+            state.groups["bob.uq:1"],
+            "&lt;img src=x onerror=alert(1)&gt;"
+        );
+    }
+
+    #[test]
+    fn every_kind_of_new_message_is_rate_limited() {
+        let requests = [
+            serde_json::json!({"SendAttachment": {
+                "target": "our.uq", "filename": "a.txt", "mime": "text/plain",
+            }}),
+            serde_json::json!({"CreatePoll": {
+                "target": "our.uq", "question": "?", "options": ["a", "b"],
+            }}),
+            serde_json::json!({"SendLocation": {
+                "target": "our.uq", "lat": 0.0, "lon": 0.0,
+            }}),
+            serde_json::json!({"SendToRoom": {"room": "lobby", "message": "hi"}}),
+            serde_json::json!({"ReceiveBatch": {"sends": []}}),
+        ];
+        for request in requests {
+            let request: ChatRequest = serde_json::from_value(request).unwrap();
+            let mut state = state();
+            state.settings.rate_limit_messages = Some(1);
+            state.rate_state.insert("bob.uq".to_string(), vec![now()]);
+            assert!(
+                matches!(
+                    respond(&address("bob.uq"), &mut state, &request),
+                    ChatResponse::Error { code: 429, .. }
+                ),
+                "{:?} got through",
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn local_processes_may_read() {
+        let our = address("our.uq");
+        let local = Address {
+            node: "our.uq".to_string(),
+            process: ProcessId::from_str("other:other:template.uq").unwrap(),
+        };
+        let remote = Address {
+            node: "bob.uq".to_string(),
+            ..local.clone()
+        };
+        assert!(impersonation(&our, &state(), &local, &ChatRequest::Stats).is_none());
+        assert!(impersonation(&our, &state(), &local, &ChatRequest::Summaries).is_none());
+        assert!(impersonation(&our, &state(), &remote, &ChatRequest::Stats).is_some());
+        assert!(impersonation(&our, &state(), &local, &edit("bob.uq")).is_some());
+
+        // The same app on another node gets past `impersonation`, but not the handler
+        let mut state = state();
+        assert!(matches!(
+            respond(&local, &mut state, &ChatRequest::Stats),
+            ChatResponse::Stats { .. }
+        ));
+        let response = respond(&address("bob.uq"), &mut state, &ChatRequest::Stats);
+        assert!(is_forbidden(&response), "{:?}", response);
+    }
+}
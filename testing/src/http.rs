@@ -0,0 +1,1186 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::{self};
+use serde::{Deserialize, Serialize};
+use uqbar_process_lib::{
+    get_payload,
+    http::{send_response, HttpServerRequest, IncomingHttpRequest, StatusCode, WsMessageType},
+    print_to_terminal,
+    vfs::open_file,
+    Address, Payload,
+};
+
+use crate::chat::*;
+use crate::state::*;
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parse the query string of a raw request path into a map
+fn parse_query(raw_path: &str) -> HashMap<String, String> {
+    let Some((_, query)) = raw_path.split_once('?') else {
+        return HashMap::new();
+    };
+
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Parse an optional query parameter, erroring if it's present but malformed
+fn parse_param<T: std::str::FromStr>(
+    query: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<T>, String> {
+    match query.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid {}: {}", key, value)),
+    }
+}
+
+/// Push every chat to a newly opened WebSocket a batch at a time, so no single frame is huge
+fn stream_history(our: &Address, state: &State, channel_id: u32) -> anyhow::Result<()> {
+    let batch_size = state.history_batch_size();
+    let mut seq = 0;
+    for (chat, originals) in &state.message_archive {
+        for batch in originals.chunks(batch_size) {
+            let mut messages = batch.to_vec();
+            prepare_for_ui(originals, &mut messages);
+            push_to_ui(
+                our,
+                channel_id,
+                &ChatEvent::HistoryChunk {
+                    seq,
+                    chat: chat.clone(),
+                    messages,
+                },
+            )?;
+            seq += 1;
+        }
+    }
+    push_to_ui(our, channel_id, &ChatEvent::HistoryDone { chunks: seq })
+}
+
+/// Earlier versions of the message `id` in `chat`, from a `/messages/history` query
+fn edit_history(state: &State, query: &HashMap<String, String>) -> ChatResponse {
+    let (Some(chat), Some(id)) = (query.get("chat"), query.get("id")) else {
+        return ChatResponse::error(StatusCode::BAD_REQUEST, "missing chat or id");
+    };
+    let Some(message) = state
+        .message_archive
+        .get(chat)
+        .and_then(|messages| messages.iter().find(|message| &message.id == id))
+    else {
+        return ChatResponse::error(
+            StatusCode::NOT_FOUND,
+            format!("no message {} in chat with {}", id, chat),
+        );
+    };
+
+    ChatResponse::EditHistory {
+        message_id: message.id.clone(),
+        content: message.content.clone(),
+        revisions: message.edit_history.clone(),
+    }
+}
+
+fn get_messages(our: &Address, state: &State, raw_path: &str) -> ChatResponse {
+    let query = parse_query(raw_path);
+
+    // `mentions=me` is shorthand for our own node name
+    if let Some(node) = query.get("mentions") {
+        let node = if node == "me" { &our.node } else { node };
+        return mentioned_messages(state, node, query.get("chat"));
+    }
+
+    if query.contains_key("q") {
+        return search_from_query(state, &query);
+    }
+
+    match parse_param::<bool>(&query, "starred") {
+        Ok(Some(true)) => return starred_messages(state),
+        Ok(_) => {}
+        Err(error) => return ChatResponse::error(StatusCode::BAD_REQUEST, error),
+    }
+
+    match parse_param::<bool>(&query, "pinned") {
+        Ok(Some(true)) => return pinned_messages(state, query.get("chat")),
+        Ok(_) => {}
+        Err(error) => return ChatResponse::error(StatusCode::BAD_REQUEST, error),
+    }
+
+    // Without a chat, keep returning the full archive for older clients, less archived chats
+    let Some(chat) = query.get("chat") else {
+        let include_archived = match parse_param::<bool>(&query, "include_archived") {
+            Ok(include_archived) => include_archived.unwrap_or(false),
+            Err(error) => return ChatResponse::error(StatusCode::BAD_REQUEST, error),
+        };
+        let mut history = history(state);
+        if let ChatResponse::History {
+            ref mut messages, ..
+        } = history
+        {
+            if !include_archived {
+                messages.retain(|chat, _| !state.archived.contains(chat));
+            }
+        }
+        return history;
+    };
+
+    let (offset, limit) = match (
+        parse_param::<usize>(&query, "offset"),
+        parse_param::<usize>(&query, "limit"),
+    ) {
+        (Ok(offset), Ok(limit)) => (offset.unwrap_or(0), limit),
+        (Err(error), _) | (_, Err(error)) => {
+            return ChatResponse::error(StatusCode::BAD_REQUEST, error)
+        }
+    };
+
+    let messages = state
+        .message_archive
+        .get(chat)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    // `before=<id>` pages back from a message, which stays put when newer ones arrive,
+    // unlike an offset. It takes the place of `offset` when both are given.
+    let (offset, limit) = match query.get("before") {
+        Some(before) => {
+            let Some(end) = messages.iter().position(|message| &message.id == before) else {
+                return ChatResponse::error(
+                    StatusCode::NOT_FOUND,
+                    format!("no message {} in chat with {}", before, chat),
+                );
+            };
+            let limit = limit.unwrap_or(end).min(end);
+            (end - limit, Some(limit))
+        }
+        None => (offset, limit),
+    };
+    let next_cursor = match offset {
+        0 => None,
+        offset => messages.get(offset).map(|message| message.id.clone()),
+    };
+
+    ChatResponse::Page {
+        chat: chat.clone(),
+        messages: messages
+            .iter()
+            .skip(offset)
+            .take(limit.unwrap_or(messages.len()))
+            .map(|message| ChatMessage {
+                quoted: message
+                    .reply_to
+                    .as_ref()
+                    .map(|reply_to| quote(messages, reply_to)),
+                ..message.clone()
+            })
+            .collect(),
+        offset,
+        total: messages.len(),
+        last_read: state.last_read.get(chat).cloned().unwrap_or_default(),
+        next_cursor,
+    }
+}
+
+/// Query params of `/messages` that go beyond reading history, kept from `/public/messages`
+const PRIVATE_PARAMS: [&str; 5] = ["starred", "mentions", "q", "pinned", "include_archived"];
+
+/// A read-only copy of the history for `/public/messages`: no searching or other views,
+/// no archived chats, and nothing that's only ours, like drafts, aliases or what we've read
+fn public_messages(our: &Address, state: &State, raw_path: &str) -> ChatResponse {
+    let query = parse_query(raw_path);
+    if let Some(param) = PRIVATE_PARAMS
+        .iter()
+        .find(|param| query.contains_key(**param))
+    {
+        return ChatResponse::error(
+            StatusCode::FORBIDDEN,
+            format!("{} is not available on the public route", param),
+        );
+    }
+    if query
+        .get("chat")
+        .is_some_and(|chat| state.archived.contains(chat))
+    {
+        return ChatResponse::error(StatusCode::NOT_FOUND, "no such chat");
+    }
+
+    let mut response = get_messages(our, state, raw_path);
+    match response {
+        ChatResponse::History {
+            ref mut messages,
+            ref mut last_read,
+            ref mut drafts,
+            ref mut unread,
+            ref mut archived,
+            ref mut aliases,
+            ref mut muted,
+            ..
+        } => {
+            for chat in messages.values_mut() {
+                chat.iter_mut().for_each(|message| message.starred = false);
+            }
+            last_read.clear();
+            drafts.clear();
+            unread.clear();
+            archived.clear();
+            aliases.clear();
+            muted.clear();
+        }
+        ChatResponse::Page {
+            ref mut messages,
+            ref mut last_read,
+            ..
+        } => {
+            messages
+                .iter_mut()
+                .for_each(|message| message.starred = false);
+            *last_read = ReadMarker::default();
+        }
+        _ => {}
+    }
+    response
+}
+
+/// Serve the attachment with the id from the path or the `id` param. Without a
+/// `chat` param, every chat is searched for it.
+fn serve_attachment(
+    our: &Address,
+    state: &State,
+    raw_path: &str,
+    path_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let query = parse_query(raw_path);
+    let Some(id) = path_id
+        .map(percent_decode)
+        .or_else(|| query.get("id").cloned())
+    else {
+        let error = ChatResponse::error(StatusCode::BAD_REQUEST, "missing id");
+        return send_json_response(error.status(), &error);
+    };
+    let chat = query.get("chat");
+    let attachment = state
+        .message_archive
+        .iter()
+        .filter(|(counterparty, _)| chat.is_none() || chat == Some(*counterparty))
+        .flat_map(|(_, messages)| messages)
+        .find(|message| message.id == id)
+        .and_then(|message| message.attachment.as_ref().map(|meta| (&id, meta)));
+    let Some((id, meta)) = attachment else {
+        let error = ChatResponse::error(StatusCode::NOT_FOUND, "no such attachment");
+        return send_json_response(error.status(), &error);
+    };
+
+    let (path, mime) = match parse_param::<bool>(&query, "thumbnail") {
+        Ok(Some(true)) if meta.thumbnail => (thumbnail_path(our, id)?, "image/png"),
+        Ok(Some(true)) => {
+            let error = ChatResponse::error(StatusCode::NOT_FOUND, "no thumbnail");
+            return send_json_response(error.status(), &error);
+        }
+        Ok(_) => (attachment_path(our, id)?, meta.mime.as_str()),
+        Err(error) => {
+            let error = ChatResponse::error(StatusCode::BAD_REQUEST, error);
+            return send_json_response(error.status(), &error);
+        }
+    };
+
+    let bytes = open_file(&path, false)?.read()?;
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), mime.to_string());
+    headers.insert(
+        "Cache-Control".to_string(),
+        "private, max-age=31536000, immutable".to_string(),
+    );
+
+    send_response(StatusCode::OK, Some(headers), bytes)
+}
+
+/// One chat as downloaded from `/messages/export`
+#[derive(Serialize)]
+struct ChatExport<'a> {
+    node: &'a str,
+    chat: &'a str,
+    exported_at: u64,
+    messages: &'a [ChatMessage],
+}
+
+/// Download one chat as pretty JSON or, with `format=txt`, a transcript of
+/// `[time] author: content` lines. Either is written straight from the archive, one
+/// message at a time, rather than through a copy of the chat.
+fn export_chat(our: &Address, state: &State, raw_path: &str) -> anyhow::Result<()> {
+    let query = parse_query(raw_path);
+    let Some(chat) = query.get("chat") else {
+        let error = ChatResponse::error(StatusCode::BAD_REQUEST, "missing chat");
+        return send_json_response(error.status(), &error);
+    };
+    let Some(messages) = state.message_archive.get(chat) else {
+        let error = ChatResponse::error(StatusCode::NOT_FOUND, format!("no chat with {}", chat));
+        return send_json_response(error.status(), &error);
+    };
+
+    let mut body = vec![];
+    let (mime, extension) = match query.get("format").map(String::as_str) {
+        None | Some("json") => {
+            let export = ChatExport {
+                node: &our.node,
+                chat,
+                exported_at: now(),
+                messages,
+            };
+            serde_json::to_writer_pretty(&mut body, &export)?;
+            ("application/json", "json")
+        }
+        Some("txt") => {
+            for message in messages.iter().filter(|message| !message.deleted) {
+                writeln!(
+                    body,
+                    "[{}] {}: {}",
+                    format_utc(message.timestamp),
+                    message.author,
+                    unsanitize(&message.content)
+                )?;
+            }
+            ("text/plain; charset=utf-8", "txt")
+        }
+        Some(format) => {
+            let error = ChatResponse::error(
+                StatusCode::BAD_REQUEST,
+                format!("unknown format {:?}; use json or txt", format),
+            );
+            return send_json_response(error.status(), &error);
+        }
+    };
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), mime.to_string());
+    headers.insert(
+        "Content-Disposition".to_string(),
+        format!(
+            "attachment; filename=\"chat-{}.{}\"",
+            chat.trim_start_matches('#'),
+            extension
+        ),
+    );
+    send_response(StatusCode::OK, Some(headers), body)
+}
+
+/// A unix timestamp as `YYYY-MM-DD HH:MM:SS` in UTC
+fn format_utc(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Describes the audio in a binary WebSocket frame. The frame is this header as JSON,
+/// a newline, then the audio bytes.
+#[derive(Debug, Deserialize)]
+struct VoiceNoteHeader {
+    target: String,
+    #[serde(default = "voice_note_mime")]
+    mime: String,
+}
+
+fn voice_note_mime() -> String {
+    "audio/ogg".to_string()
+}
+
+/// Send a voice note recorded in the UI as an ordinary attachment, through the same checks.
+/// It's served back from `/attachment/<id>` with its own mime, so `<audio>` can stream it.
+fn send_voice_note(
+    our: &Address,
+    state: &mut State,
+    source: &Address,
+    frame: &[u8],
+) -> anyhow::Result<ChatResponse> {
+    let header = frame
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .and_then(|end| {
+            Some((
+                serde_json::from_slice::<VoiceNoteHeader>(&frame[..end]).ok()?,
+                end,
+            ))
+        });
+    let Some((header, end)) = header else {
+        return Ok(ChatResponse::error(
+            StatusCode::BAD_REQUEST,
+            "voice notes need a JSON header line",
+        ));
+    };
+    let audio = &frame[end + 1..];
+    if !is_valid_node_name(&header.target) || header.target == our.node {
+        return Ok(ChatResponse::error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid node name {:?}", header.target),
+        ));
+    }
+    if !header.mime.starts_with("audio/") {
+        return Ok(ChatResponse::error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("{} is not audio", header.mime),
+        ));
+    }
+
+    let send = ChatRequest::SendAttachment {
+        target: header.target,
+        filename: "voice-note".to_string(),
+        mime: header.mime.clone(),
+        timestamp: None,
+        id: None,
+    };
+    let payload = Payload {
+        mime: Some(header.mime),
+        bytes: audio.to_vec(),
+    };
+    handle_chat_request_with_payload(
+        our,
+        state,
+        source,
+        &serde_json::to_vec(&send)?,
+        false,
+        Some(payload),
+    )
+}
+
+/// Search from the `q`, `chat` and `limit` query parameters of a GET request
+fn search_from_query(state: &State, query: &HashMap<String, String>) -> ChatResponse {
+    let Some(q) = query.get("q") else {
+        return ChatResponse::error(StatusCode::BAD_REQUEST, "missing q");
+    };
+    match parse_param::<usize>(query, "limit") {
+        Ok(limit) => search(state, q, query.get("chat"), limit),
+        Err(error) => ChatResponse::error(StatusCode::BAD_REQUEST, error),
+    }
+}
+
+/// The bound path a request arrived on, without our process prefix or query string
+fn request_path<'a>(our: &Address, raw_path: &'a str) -> &'a str {
+    let path = raw_path.split('?').next().unwrap_or_default();
+    path.strip_prefix(&format!("/{}", our.process))
+        .unwrap_or(path)
+}
+
+/// An endpoint we serve over HTTP
+enum Route<'a> {
+    PublicMessages,
+    GetMessages,
+    SendMessage,
+    ClearMessages,
+    /// Carries the id when it's in the path rather than the query
+    GetAttachment(Option<&'a str>),
+    UploadAttachment,
+    EditHistory,
+    Export,
+    ExportChat,
+    Import,
+    Scheduled,
+    Summaries,
+    Blocked,
+    Filter,
+    Presence,
+    Search,
+    Status,
+    GetSettings,
+    UpdateSettings,
+    Stats,
+    Unread,
+    MessageRequests,
+    ListChats,
+    Conversations,
+    ChatSettings,
+    CreateChat,
+    ListContacts,
+    AddContact,
+    RemoveContact,
+    DeleteChat,
+}
+
+/// Which endpoint serves `method` on `path`, or the status to answer with when none does
+fn route<'a>(path: &'a str, method: &str, public_read: bool) -> Result<Route<'a>, StatusCode> {
+    let route = match (path, method) {
+        ("/public/messages", "GET") if public_read => Route::PublicMessages,
+        ("/public/messages", _) if public_read => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        ("/messages", "GET") => Route::GetMessages,
+        ("/messages", "POST") => Route::SendMessage,
+        ("/messages", "DELETE") => Route::ClearMessages,
+        ("/messages/attachment", "GET") => Route::GetAttachment(None),
+        ("/messages/attachment", "POST") => Route::UploadAttachment,
+        ("/messages/history", "GET") => Route::EditHistory,
+        ("/messages/stats", "GET") => Route::Stats,
+        ("/messages/unread", "GET") => Route::Unread,
+        ("/messages/requests", "GET") => Route::MessageRequests,
+        ("/messages/export", "GET") => Route::ExportChat,
+        ("/export", "GET") => Route::Export,
+        ("/import", "POST") => Route::Import,
+        ("/scheduled", "GET") => Route::Scheduled,
+        ("/summaries", "GET") => Route::Summaries,
+        ("/blocked", "GET") => Route::Blocked,
+        ("/filter", "GET") => Route::Filter,
+        ("/presence", "GET") => Route::Presence,
+        ("/search", "GET") => Route::Search,
+        ("/status", "GET") => Route::Status,
+        ("/settings", "GET") => Route::GetSettings,
+        ("/settings", "POST") => Route::UpdateSettings,
+        ("/chats", "GET") => Route::ListChats,
+        ("/conversations", "GET") => Route::Conversations,
+        ("/chats/settings", "PATCH") => Route::ChatSettings,
+        ("/contacts", "GET") => Route::ListContacts,
+        ("/contacts", "POST") => Route::AddContact,
+        ("/contacts", "DELETE") => Route::RemoveContact,
+        ("/chats", "POST") => Route::CreateChat,
+        ("/chats", "DELETE") => Route::DeleteChat,
+        (path, "GET") if path.starts_with("/attachment/") => {
+            Route::GetAttachment(path.strip_prefix("/attachment/"))
+        }
+        (path, _) if path.starts_with("/attachment/") => {
+            return Err(StatusCode::METHOD_NOT_ALLOWED)
+        }
+        (
+            "/messages"
+            | "/messages/attachment"
+            | "/messages/history"
+            | "/messages/stats"
+            | "/messages/unread"
+            | "/messages/requests"
+            | "/messages/export"
+            | "/export"
+            | "/import"
+            | "/scheduled"
+            | "/summaries"
+            | "/blocked"
+            | "/filter"
+            | "/presence"
+            | "/search"
+            | "/status"
+            | "/settings"
+            | "/chats"
+            | "/chats/settings"
+            | "/conversations"
+            | "/contacts",
+            _,
+        ) => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+    Ok(route)
+}
+
+fn send_json_response<T: Serialize>(status: StatusCode, body: &T) -> anyhow::Result<()> {
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+
+    send_response(status, Some(headers), serde_json::to_vec(body)?)
+}
+
+pub(crate) fn handle_http_server_request(
+    our: &Address,
+    state: &mut State,
+    source: &Address,
+    ipc: &[u8],
+) -> anyhow::Result<()> {
+    let Ok(server_request) = serde_json::from_slice::<HttpServerRequest>(ipc) else {
+        // Fail silently if we can't parse the request
+        return Ok(());
+    };
+
+    match server_request {
+        HttpServerRequest::WebSocketOpen { channel_id, .. } => {
+            state.channels.insert(channel_id, None);
+            stream_history(our, state, channel_id)?;
+        }
+        HttpServerRequest::WebSocketPush {
+            channel_id,
+            message_type,
+        } => {
+            print_to_terminal(0, "11");
+            let Some(payload) = get_payload() else {
+                return Ok(());
+            };
+
+            // A tab showing one chat only wants that chat's new messages
+            if let Ok(ChatRequest::Subscribe { chat }) =
+                serde_json::from_slice::<ChatRequest>(&payload.bytes)
+            {
+                state.channels.insert(channel_id, chat);
+                return Ok(());
+            }
+
+            // Binary frames carry voice notes; everything else is a JSON chat request
+            let response = match message_type {
+                WsMessageType::Binary => send_voice_note(our, state, source, &payload.bytes)?,
+                _ => handle_chat_request(our, state, source, &payload.bytes, false)?,
+            };
+
+            // Report errors back to the UI over the same channel
+            if let ChatResponse::Error { .. } = response {
+                push_to_ui(our, channel_id, &response)?;
+            }
+        }
+        HttpServerRequest::WebSocketClose(channel_id) => {
+            state.channels.remove(&channel_id);
+        }
+        HttpServerRequest::Http(IncomingHttpRequest {
+            method,
+            raw_path,
+            headers,
+            ..
+        }) => {
+            let path = request_path(our, &raw_path);
+            let route = match route(path, method.as_str(), state.settings.public_read) {
+                Ok(route) => route,
+                Err(status) => return send_response(status, None, vec![]),
+            };
+            match route {
+                // Anyone may read through the public route, but never write or see our own state
+                Route::PublicMessages => {
+                    purge_expired(our, state)?;
+                    let response = public_messages(our, state, &raw_path);
+                    send_json_response(response.status(), &response)?;
+                }
+                // Download an attachment
+                Route::GetAttachment(path_id) => {
+                    serve_attachment(our, state, &raw_path, path_id)?;
+                }
+                // Upload an attachment, with the bytes as the request body
+                Route::UploadAttachment => {
+                    let query = parse_query(&raw_path);
+                    let (Some(target), Some(filename)) =
+                        (query.get("target"), query.get("filename"))
+                    else {
+                        let error = ChatResponse::error(
+                            StatusCode::BAD_REQUEST,
+                            "missing target or filename",
+                        );
+                        return send_json_response(error.status(), &error);
+                    };
+                    let mime = headers
+                        .iter()
+                        .find(|(key, _)| key.eq_ignore_ascii_case("content-type"))
+                        .map(|(_, value)| value.clone())
+                        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+                    let send_attachment = ChatRequest::SendAttachment {
+                        target: target.clone(),
+                        filename: filename.clone(),
+                        mime,
+                        timestamp: None,
+                        id: None,
+                    };
+                    let response = handle_chat_request(
+                        our,
+                        state,
+                        source,
+                        &serde_json::to_vec(&send_attachment)?,
+                        true,
+                    )?;
+                    send_json_response(response.status(), &response)?;
+                }
+                // Download every chat as a JSON backup
+                Route::Export => {
+                    purge_expired(our, state)?;
+                    let export = ArchiveExport {
+                        node: our.node.clone(),
+                        exported_at: now(),
+                        message_archive: state.message_archive.clone(),
+                    };
+                    let mut headers = HashMap::new();
+                    headers.insert("Content-Type".to_string(), "application/json".to_string());
+                    headers.insert(
+                        "Content-Disposition".to_string(),
+                        "attachment; filename=\"chat-export.json\"".to_string(),
+                    );
+                    send_response(StatusCode::OK, Some(headers), serde_json::to_vec(&export)?)?;
+                }
+                // One chat, like `/messages/export?chat=bob.uq&format=txt`
+                Route::ExportChat => {
+                    purge_expired(our, state)?;
+                    export_chat(our, state, &raw_path)?;
+                }
+                // Restore a backup from `/export`, keeping what we already have
+                Route::Import => {
+                    let export = get_payload().and_then(|payload| {
+                        serde_json::from_slice::<ArchiveExport>(&payload.bytes).ok()
+                    });
+                    let Some(export) = export else {
+                        let error = ChatResponse::error(
+                            StatusCode::BAD_REQUEST,
+                            "body must be an archive from /export",
+                        );
+                        return send_json_response(error.status(), &error);
+                    };
+                    let added = state.import(export.message_archive);
+                    save_state(our, state)?;
+
+                    let response = ChatResponse::Imported { added };
+                    send_json_response(response.status(), &response)?;
+                }
+                // Messages waiting to be sent later
+                Route::Scheduled => {
+                    let response = scheduled_messages(state);
+                    send_json_response(response.status(), &response)?;
+                }
+                // Latest message and unread count per chat
+                Route::Summaries => {
+                    purge_expired(our, state)?;
+                    let response = summaries(state);
+                    send_json_response(response.status(), &response)?;
+                }
+                // Nodes we drop messages from
+                Route::Blocked => {
+                    let mut nodes: Vec<String> = state.blocked.iter().cloned().collect();
+                    nodes.sort();
+                    let response = ChatResponse::Blocked { nodes };
+                    send_json_response(response.status(), &response)?;
+                }
+                // The content filter's words and action
+                Route::Filter => {
+                    let response = ChatResponse::Filter {
+                        words: state.filter.words.clone(),
+                        action: state.filter.action,
+                    };
+                    send_json_response(response.status(), &response)?;
+                }
+                // Whether we're up, and how much we hold
+                Route::Status => {
+                    let response = ChatResponse::Status {
+                        node: our.node.clone(),
+                        conversations: state.message_archive.len(),
+                        messages: state.message_archive.values().map(Vec::len).sum(),
+                        websocket_channels: state.channels.len(),
+                        uptime_secs: now().saturating_sub(state.started_at),
+                    };
+                    send_json_response(response.status(), &response)?;
+                }
+                Route::GetSettings => {
+                    let response = ChatResponse::Settings(state.settings.clone());
+                    send_json_response(response.status(), &response)?;
+                }
+                // The body holds just the settings to change, like `{"send_timeout_secs": 10}`
+                Route::UpdateSettings => {
+                    let changes = get_payload().and_then(|payload| {
+                        serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(
+                            &payload.bytes,
+                        )
+                        .ok()
+                    });
+                    let Some(changes) = changes else {
+                        let error = ChatResponse::error(
+                            StatusCode::BAD_REQUEST,
+                            "body must be a JSON object of settings",
+                        );
+                        return send_json_response(error.status(), &error);
+                    };
+                    let serde_json::Value::Object(mut merged) =
+                        serde_json::to_value(&state.settings)?
+                    else {
+                        unreachable!("settings serialize to an object");
+                    };
+                    merged.extend(changes);
+                    let settings = match serde_json::from_value::<Settings>(merged.into()) {
+                        Ok(settings) => settings,
+                        Err(e) => {
+                            let error = ChatResponse::error(
+                                StatusCode::BAD_REQUEST,
+                                format!("invalid settings: {}", e),
+                            );
+                            return send_json_response(error.status(), &error);
+                        }
+                    };
+                    let response = handle_chat_request(
+                        our,
+                        state,
+                        source,
+                        &serde_json::to_vec(&ChatRequest::UpdateSettings { settings })?,
+                        true,
+                    )?;
+                    send_json_response(response.status(), &response)?;
+                }
+                // Which nodes answered last time we tried them
+                Route::Presence => {
+                    let response = ChatResponse::Presence {
+                        presence: state.presence.clone(),
+                        last_seen: state.last_seen.clone(),
+                    };
+                    send_json_response(response.status(), &response)?;
+                }
+                // Counts per chat, kept up to date as messages come and go
+                Route::Stats => {
+                    let response = ChatResponse::Stats {
+                        stats: state.stats.clone(),
+                    };
+                    send_json_response(response.status(), &response)?;
+                }
+                // Messages from nodes we haven't accepted a chat with yet
+                Route::MessageRequests => {
+                    let response = handle_chat_request(
+                        our,
+                        state,
+                        source,
+                        &serde_json::to_vec(&ChatRequest::ListMessageRequests)?,
+                        true,
+                    )?;
+                    send_json_response(response.status(), &response)?;
+                }
+                // Just the unread counts, cheap enough to poll
+                Route::Unread => {
+                    let response = ChatResponse::Unread {
+                        total: state.unread.values().sum(),
+                        chats: state.unread.clone(),
+                    };
+                    send_json_response(response.status(), &response)?;
+                }
+                // Earlier versions of an edited message
+                Route::EditHistory => {
+                    let response = edit_history(state, &parse_query(&raw_path));
+                    send_json_response(response.status(), &response)?;
+                }
+                // Search across all chats
+                Route::Search => {
+                    let response = search_from_query(state, &parse_query(&raw_path));
+                    send_json_response(response.status(), &response)?;
+                }
+                // Get all messages, or a page of one chat
+                Route::GetMessages => {
+                    purge_expired(our, state)?;
+                    let response = get_messages(our, state, &raw_path);
+                    send_json_response(response.status(), &response)?;
+                }
+                // Send a message
+                Route::SendMessage => {
+                    print_to_terminal(0, "1");
+                    let Some(payload) = get_payload() else {
+                        return send_json_response(
+                            StatusCode::BAD_REQUEST,
+                            &ChatResponse::error(StatusCode::BAD_REQUEST, "missing request body"),
+                        );
+                    };
+                    print_to_terminal(0, "2");
+                    // A plain text body is the message itself, for sending with a curl one-liner
+                    let is_plain_text = payload
+                        .mime
+                        .as_deref()
+                        .is_some_and(|mime| mime.starts_with("text/plain"));
+                    let ipc = if is_plain_text {
+                        let Some(target) = parse_query(&raw_path).remove("target") else {
+                            let error = ChatResponse::error(
+                                StatusCode::BAD_REQUEST,
+                                "plain text messages need a target",
+                            );
+                            return send_json_response(error.status(), &error);
+                        };
+                        let Ok(message) = String::from_utf8(payload.bytes) else {
+                            let error = ChatResponse::error(
+                                StatusCode::BAD_REQUEST,
+                                "plain text messages must be UTF-8",
+                            );
+                            return send_json_response(error.status(), &error);
+                        };
+                        serde_json::to_vec(&ChatRequest::Send {
+                            target,
+                            message,
+                            timestamp: None,
+                            id: None,
+                            reply_to: None,
+                            format: MessageFormat::default(),
+                            expires_in_seconds: None,
+                            forwarded_from: None,
+                            kind: MessageKind::Text,
+                            seq: None,
+                            client_key: None,
+                            sealed: None,
+                            metadata: HashMap::new(),
+                            signature: None,
+                        })?
+                    } else {
+                        payload.bytes
+                    };
+                    let response = handle_chat_request(our, state, source, &ipc, true)?;
+
+                    // Send an http response via the http server
+                    send_json_response(response.status(), &response)?;
+                }
+                // The sidebar: one summary per chat, without the messages
+                Route::ListChats => {
+                    purge_expired(our, state)?;
+                    let response = summaries(state);
+                    send_json_response(response.status(), &response)?;
+                }
+                // Just the names of who we chat with, for a first render
+                Route::Conversations => {
+                    let response = conversations(state);
+                    send_json_response(response.status(), &response)?;
+                }
+                // Change some of a chat's notification settings, like
+                // `/chats/settings?chat=bob.uq` with `{"notify": "mentions_only"}`
+                Route::ChatSettings => {
+                    let Some(chat) = parse_query(&raw_path).get("chat").cloned() else {
+                        let error = ChatResponse::error(StatusCode::BAD_REQUEST, "missing chat");
+                        return send_json_response(error.status(), &error);
+                    };
+                    let changes = get_payload().and_then(|payload| {
+                        serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(
+                            &payload.bytes,
+                        )
+                        .ok()
+                    });
+                    let Some(changes) = changes else {
+                        let error = ChatResponse::error(
+                            StatusCode::BAD_REQUEST,
+                            "body must be a JSON object of chat settings",
+                        );
+                        return send_json_response(error.status(), &error);
+                    };
+                    let serde_json::Value::Object(mut merged) =
+                        serde_json::to_value(state.chat_settings(&chat))?
+                    else {
+                        unreachable!("chat settings serialize to an object");
+                    };
+                    merged.extend(changes);
+                    let settings = match serde_json::from_value::<ChatSettings>(merged.into()) {
+                        Ok(settings) => settings,
+                        Err(e) => {
+                            let error = ChatResponse::error(
+                                StatusCode::BAD_REQUEST,
+                                format!("invalid chat settings: {}", e),
+                            );
+                            return send_json_response(error.status(), &error);
+                        }
+                    };
+                    let response = handle_chat_request(
+                        our,
+                        state,
+                        source,
+                        &serde_json::to_vec(&ChatRequest::SetChatSettings { chat, settings })?,
+                        true,
+                    )?;
+
+                    match response {
+                        ChatResponse::Ack => send_response(StatusCode::NO_CONTENT, None, vec![])?,
+                        _ => send_json_response(response.status(), &response)?,
+                    }
+                }
+                // Start or delete a chat, like `/chats?target=bob.uq&notify=true`
+                Route::CreateChat | Route::DeleteChat => {
+                    let query = parse_query(&raw_path);
+                    let Some(target) = query.get("target").cloned() else {
+                        let error = ChatResponse::error(StatusCode::BAD_REQUEST, "missing target");
+                        return send_json_response(error.status(), &error);
+                    };
+                    let chat_request = match route {
+                        Route::CreateChat => ChatRequest::CreateChat { target },
+                        _ => ChatRequest::DeleteChat {
+                            target,
+                            notify: matches!(parse_param::<bool>(&query, "notify"), Ok(Some(true))),
+                        },
+                    };
+                    let response = handle_chat_request(
+                        our,
+                        state,
+                        source,
+                        &serde_json::to_vec(&chat_request)?,
+                        true,
+                    )?;
+
+                    match response {
+                        ChatResponse::Ack => send_response(StatusCode::NO_CONTENT, None, vec![])?,
+                        _ => send_json_response(response.status(), &response)?,
+                    }
+                }
+                // Contacts, like `/contacts?node=bob.uq&nickname=Bob`
+                Route::ListContacts | Route::AddContact | Route::RemoveContact => {
+                    let query = parse_query(&raw_path);
+                    let chat_request = match (route, query.get("node").cloned()) {
+                        (Route::ListContacts, _) => ChatRequest::ListContacts,
+                        (Route::AddContact, Some(node)) => ChatRequest::AddContact {
+                            node,
+                            nickname: query.get("nickname").cloned(),
+                        },
+                        (_, Some(node)) => ChatRequest::RemoveContact { node },
+                        (_, None) => {
+                            let error =
+                                ChatResponse::error(StatusCode::BAD_REQUEST, "missing node");
+                            return send_json_response(error.status(), &error);
+                        }
+                    };
+                    let response = handle_chat_request(
+                        our,
+                        state,
+                        source,
+                        &serde_json::to_vec(&chat_request)?,
+                        true,
+                    )?;
+
+                    match response {
+                        ChatResponse::Ack => send_response(StatusCode::NO_CONTENT, None, vec![])?,
+                        _ => send_json_response(response.status(), &response)?,
+                    }
+                }
+                // Clear one chat, or all of them
+                Route::ClearMessages => {
+                    let clear = ChatRequest::Clear {
+                        counterparty: parse_query(&raw_path).get("chat").cloned(),
+                    };
+                    let response = handle_chat_request(
+                        our,
+                        state,
+                        source,
+                        &serde_json::to_vec(&clear)?,
+                        true,
+                    )?;
+
+                    match response {
+                        ChatResponse::Ack => send_response(StatusCode::NO_CONTENT, None, vec![])?,
+                        _ => send_json_response(response.status(), &response)?,
+                    }
+                }
+            }
+        }
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uqbar_process_lib::ProcessId;
+
+    use super::*;
+
+    fn message(id: &str, author: &str) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            author: author.to_string(),
+            content: "hello".to_string(),
+            starred: true,
+            ..ChatMessage::default()
+        }
+    }
+
+    fn public_state() -> State {
+        let mut state = State::default();
+        state.settings.public_read = true;
+        for chat in ["bob.uq", "carol.uq"] {
+            state.message_archive.insert(
+                chat.to_string(),
+                vec![message(&format!("{}:1", chat), chat)],
+            );
+            state.unread.insert(chat.to_string(), 1);
+            state.drafts.insert(chat.to_string(), "draft".to_string());
+            state.aliases.insert(chat.to_string(), "friend".to_string());
+            state.muted.insert(chat.to_string(), None);
+            state
+                .last_read
+                .insert(chat.to_string(), ReadMarker::default());
+        }
+        state.archived.insert("carol.uq".to_string());
+        state
+    }
+
+    #[test]
+    fn public_route_only_reads_history() {
+        let our = Address {
+            node: "our.uq".to_string(),
+            process: ProcessId::from_str("testing:testing:template.uq").unwrap(),
+        };
+        let state = public_state();
+        for query in [
+            "?starred=true",
+            "?pinned=true",
+            "?mentions=me",
+            "?q=hello",
+            "?include_archived=true",
+        ] {
+            let path = format!("/public/messages{}", query);
+            assert!(
+                matches!(
+                    public_messages(&our, &state, &path),
+                    ChatResponse::Error { code: 403, .. }
+                ),
+                "{} was let through",
+                query
+            );
+        }
+        assert!(matches!(
+            public_messages(&our, &state, "/public/messages?chat=carol.uq"),
+            ChatResponse::Error { code: 404, .. }
+        ));
+
+        let ChatResponse::History {
+            messages,
+            last_read,
+            drafts,
+            unread,
+            archived,
+            aliases,
+            muted,
+            ..
+        } = public_messages(&our, &state, "/public/messages")
+        else {
+            panic!("no history");
+        };
+        assert_eq!(messages.keys().collect::<Vec<_>>(), vec!["bob.uq"]);
+        assert!(!messages["bob.uq"][0].starred);
+        assert!(last_read.is_empty() && drafts.is_empty() && unread.is_empty());
+        assert!(archived.is_empty() && aliases.is_empty() && muted.is_empty());
+
+        let ChatResponse::Page { messages, .. } =
+            public_messages(&our, &state, "/public/messages?chat=bob.uq")
+        else {
+            panic!("no page");
+        };
+        assert_eq!(messages.len(), 1);
+        assert!(!messages[0].starred);
+    }
+}
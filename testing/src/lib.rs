@@ -1,16 +1,20 @@
-use std::collections::HashMap;
-
 use anyhow::{self};
-use serde::{Deserialize, Serialize};
+use ed25519_dalek::SigningKey;
+use rand_core::OsRng;
 use uqbar_process_lib::{
-    await_message, get_payload,
-    http::{
-        bind_http_path, handle_ui_asset_request, send_response, send_ws_push, serve_index_html,
-        serve_ui, HttpServerRequest, IncomingHttpRequest, StatusCode, WsMessageType, bind_ws_path,
-    },
-    print_to_terminal, Address, Message, Payload, ProcessId, Request, Response,
+    await_message,
+    http::{bind_http_path, bind_ws_path, serve_ui, StatusCode},
+    print_to_terminal, Address, Message, Response,
 };
 
+mod chat;
+mod http;
+mod state;
+
+use chat::*;
+use http::*;
+use state::*;
+
 wit_bindgen::generate!({
     path: "wit",
     world: "process",
@@ -19,235 +23,39 @@ wit_bindgen::generate!({
     },
 });
 
-#[derive(Debug, Serialize, Deserialize)]
-enum ChatRequest {
-    Send { target: String, message: String },
-    History,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-enum ChatResponse {
-    Ack,
-    History { messages: MessageArchive },
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct ChatMessage {
-    author: String,
-    content: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct NewMessage {
-    chat: String,
-    author: String,
-    content: String,
-}
-
-type MessageArchive = HashMap<String, Vec<ChatMessage>>;
-
-fn handle_http_server_request(
-    our: &Address,
-    message_archive: &mut MessageArchive,
-    source: &Address,
-    ipc: &[u8],
-    our_channel_id: &mut u32,
-) -> anyhow::Result<()> {
-    let Ok(server_request) = serde_json::from_slice::<HttpServerRequest>(ipc) else {
-        // Fail silently if we can't parse the request
-        return Ok(());
-    };
-
-    match server_request {
-        HttpServerRequest::WebSocketOpen { channel_id, .. } => {
-            // Set our channel_id to the newly opened channel
-            // Note: this code could be improved to support multiple channels
-            *our_channel_id = channel_id;
-        }
-        HttpServerRequest::WebSocketPush { .. } => {
-            print_to_terminal(0, "11");
-            let Some(payload) = get_payload() else {
+fn handle_message(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    let message = match await_message() {
+        Ok(message) => message,
+        // A message we forwarded never got a response. Nothing else is lost by a failed
+        // receive, so it's logged and the loop in `init` carries on.
+        Err(send_error) => {
+            if let Some(PingContext { ping }) = send_error
+                .context
+                .as_deref()
+                .and_then(|context| serde_json::from_slice(context).ok())
+            {
+                set_presence(our, state, &ping, false);
                 return Ok(());
-            };
-
-            handle_chat_request(
-                our,
-                message_archive,
-                our_channel_id,
-                source,
-                &payload.bytes,
-                false,
-            )?;
-        }
-        HttpServerRequest::WebSocketClose(_channel_id) => {}
-        HttpServerRequest::Http(IncomingHttpRequest { method, .. }) => {
-            match method.as_str() {
-                // Get all messages
-                "GET" => {
-                    let mut headers = HashMap::new();
-                    headers.insert("Content-Type".to_string(), "application/json".to_string());
-
-                    send_response(
-                        StatusCode::OK,
-                        Some(headers),
-                        serde_json::to_vec(&ChatResponse::History {
-                            messages: message_archive.clone(),
-                        })
-                        .unwrap(),
-                    )?;
-                }
-                // Send a message
-                "POST" => {
-                    print_to_terminal(0, "1");
-                    let Some(payload) = get_payload() else {
-                        return Ok(());
-                    };
-                    print_to_terminal(0, "2");
-                    handle_chat_request(
-                        our,
-                        message_archive,
-                        our_channel_id,
-                        source,
-                        &payload.bytes,
-                        true,
-                    )?;
-
-                    // Send an http response via the http server
-                    send_response(StatusCode::CREATED, None, vec![])?;
-                }
-                _ => {
-                    // Method not allowed
-                    send_response(StatusCode::METHOD_NOT_ALLOWED, None, vec![])?;
-                }
             }
-        }
-    };
-
-    Ok(())
-}
-
-fn handle_chat_request(
-    our: &Address,
-    message_archive: &mut MessageArchive,
-    channel_id: &mut u32,
-    source: &Address,
-    ipc: &[u8],
-    is_http: bool,
-) -> anyhow::Result<()> {
-    print_to_terminal(0, "3");
-    let Ok(chat_request) = serde_json::from_slice::<ChatRequest>(ipc) else {
-        // Fail silently if we can't parse the request
-        return Ok(());
-    };
-    print_to_terminal(0, "4");
-
-    match chat_request {
-        ChatRequest::Send {
-            ref target,
-            ref message,
-        } => {
-            print_to_terminal(0, "5");
-            // counterparty will be the other node in the chat with us
-            let (counterparty, author) = if target == &our.node {
-                (&source.node, source.node.clone())
-            } else {
-                (target, our.node.clone())
-            };
-
-            print_to_terminal(0, "6");
-            // If the target is not us, send a request to the target
-
-            if target != &our.node {
-                print_to_terminal(0, &format!("new message from {}: {}", source.node, message));
-
-                let _ = Request::new()
-                    .target(Address {
-                        node: target.clone(),
-                        process: ProcessId::from_str("testing:testing:template.uq")?,
-                    })
-                    .ipc(ipc)
-                    .send_and_await_response(5)?
-                    .unwrap();
-            }
-
-            // Retreive the message archive for the counterparty, or create a new one if it doesn't exist
-            let messages = match message_archive.get_mut(counterparty) {
-                Some(messages) => messages,
-                None => {
-                    message_archive.insert(counterparty.clone(), Vec::new());
-                    message_archive.get_mut(counterparty).unwrap()
-                }
-            };
-
-            let new_message = ChatMessage {
-                author: author.clone(),
-                content: message.clone(),
-            };
-
-            // If this is an HTTP request, handle the response in the calling function
-            if is_http {
-                // Add the new message to the archive
-                messages.push(new_message);
+            let Some(context) = send_error
+                .context
+                .as_deref()
+                .and_then(|context| serde_json::from_slice::<DeliveryContext>(context).ok())
+            else {
+                print_to_terminal(
+                    0,
+                    &format!(
+                        "testing: send error to {} - {:?}",
+                        send_error.target, send_error.kind
+                    ),
+                );
                 return Ok(());
-            }
-
-            // If this is not an HTTP request, send a response to the other node
-            Response::new()
-                .ipc(serde_json::to_vec(&ChatResponse::Ack).unwrap())
-                .send()
-                .unwrap();
-
-            // Add the new message to the archive
-            messages.push(new_message);
-
-            // Generate a Payload for the new message
-            let payload = Payload {
-                mime: Some("application/json".to_string()),
-                bytes: serde_json::json!({
-                    "NewMessage": NewMessage {
-                        chat: counterparty.clone(),
-                        author,
-                        content: message.clone(),
-                    }
-                })
-                .to_string()
-                .as_bytes()
-                .to_vec(),
             };
-
-            // Send a WebSocket message to the http server in order to update the UI
-            send_ws_push(
-                our.node.clone(),
-                channel_id.clone(),
-                WsMessageType::Text,
-                payload,
-            )?;
-        }
-        ChatRequest::History => {
-            // If this is an HTTP request, send a response to the http server
-
-            Response::new()
-                .ipc(
-                    serde_json::to_vec(&ChatResponse::History {
-                        messages: message_archive.clone(),
-                    })
-                    .unwrap(),
-                )
-                .send()
-                .unwrap();
+            set_presence(our, state, &context.chat, false);
+            return schedule_retry(our, state, context);
         }
     };
 
-    Ok(())
-}
-
-fn handle_message(
-    our: &Address,
-    message_archive: &mut MessageArchive,
-    channel_id: &mut u32,
-) -> anyhow::Result<()> {
-    let message = await_message().unwrap();
-
     // This is for serving static assets dynamically
     // let ipc = message.ipc();
     // if let Ok(request) = serde_json::from_slice::<HttpServerRequest>(ipc) {
@@ -262,19 +70,72 @@ fn handle_message(
     // }
 
     match message {
-        Message::Response { .. } => {
+        Message::Response {
+            ref source,
+            ref ipc,
+            ref context,
+            ..
+        } => {
+            // A timer we set for a disappearing message has gone off
+            if source.process.to_string() == "timer:sys:uqbar" {
+                return purge_expired(our, state);
+            }
+            // A presence ping got an answer
+            if let Some(PingContext { ping }) = context
+                .as_deref()
+                .and_then(|context| serde_json::from_slice(context).ok())
+            {
+                set_presence(our, state, &ping, true);
+                return Ok(());
+            }
+            // The counterparty's answer to a message we forwarded
+            if let Some(context) = context
+                .as_deref()
+                .and_then(|context| serde_json::from_slice::<DeliveryContext>(context).ok())
+            {
+                // Even a rejection means they're up
+                set_presence(our, state, &source.node, true);
+                let (context, status) = delivery_outcome(context, ipc);
+                return update_delivery_status(our, state, context, status);
+            }
             print_to_terminal(0, &format!("testing: got response - {:?}", message));
             return Ok(());
         }
         Message::Request {
             ref source,
             ref ipc,
+            expects_response,
             ..
         } => {
-            // Requests that come from other nodes running this app
-            handle_chat_request(our, message_archive, channel_id, source, &ipc, false)?;
-            // Requests that come from our http server
-            handle_http_server_request(our, message_archive, source, ipc, channel_id)?;
+            if source.process.to_string() == "http_server:sys:uqbar" {
+                // Requests that come from our http server
+                handle_http_server_request(our, state, source, ipc)?;
+            } else {
+                // Requests that come from other nodes running this app
+                let reason = serde_json::from_slice::<ChatRequest>(ipc)
+                    .ok()
+                    .and_then(|chat_request| impersonation(our, state, source, &chat_request));
+                let response = match reason {
+                    Some(reason) => {
+                        print_to_terminal(
+                            0,
+                            &format!("testing: dropped request from {}: {}", source, reason),
+                        );
+                        ChatResponse::error(StatusCode::FORBIDDEN, reason)
+                    }
+                    None => {
+                        // Other processes on our node may ask too
+                        if source.node != our.node {
+                            set_presence(our, state, &source.node, true);
+                        }
+                        handle_chat_request(our, state, source, ipc, false)?
+                    }
+                };
+                // Fire-and-forget requests like typing indicators get no response
+                if expects_response.is_some() {
+                    Response::new().ipc(serde_json::to_vec(&response)?).send()?;
+                }
+            }
         }
     }
 
@@ -287,13 +148,77 @@ impl Guest for Component {
         print_to_terminal(0, "testing: begin");
 
         let our = Address::from_str(&our).unwrap();
-        let mut message_archive: MessageArchive = HashMap::new();
-        let mut channel_id = 0;
+        let mut state = load_state(&our);
+        state.process = our.process.to_string();
+        state.node = our.node.clone();
+        if state.signing_key.is_none() {
+            state.signing_key = Some(SigningKey::generate(&mut OsRng).to_bytes());
+            if let Err(e) = save_state(&our, &state) {
+                print_to_terminal(0, &format!("testing: saving signing key: {:?}", e));
+            }
+        }
+        state.started_at = now();
+        print_to_terminal(
+            0,
+            &format!("testing: send timeout {}s", state.send_timeout()),
+        );
+
+        // Disappearing messages may have come due while we were down. Timers don't outlive
+        // us, so re-arm one for whatever is next: an expiry, a schedule or a retry.
+        let _ = purge_expired(&our, &mut state);
+        let next_wakeup = state
+            .message_archive
+            .values()
+            .flatten()
+            .filter_map(|message| message.expires_at)
+            .chain(state.scheduled.iter().map(|scheduled| scheduled.deliver_at))
+            .chain(state.pending.iter().map(|pending| pending.next_retry))
+            .min();
+        if let Some(wakeup) = next_wakeup {
+            if let Err(e) = set_timer(&our, wakeup.saturating_sub(now())) {
+                print_to_terminal(0, format!("testing: timer: {:?}", e,).as_str());
+            }
+        }
 
-        // Bind HTTP path /messages
-        match bind_http_path("/messages", true, false) {
-            Ok(_) => {}
-            Err(e) => {
+        // The first round of presence pings also sets the timer for the next
+        if let Err(e) = ping_counterparties(&our, &mut state) {
+            print_to_terminal(0, format!("testing: presence: {:?}", e,).as_str());
+        }
+
+        // Bind HTTP paths for messages, attachments and search
+        for path in [
+            "/messages",
+            "/messages/attachment",
+            "/messages/history",
+            "/messages/stats",
+            "/messages/unread",
+            "/messages/requests",
+            "/messages/export",
+            "/attachment/:id",
+            "/presence",
+            "/search",
+            "/scheduled",
+            "/summaries",
+            "/export",
+            "/import",
+            "/blocked",
+            "/filter",
+            "/status",
+            "/settings",
+            "/chats",
+            "/chats/settings",
+            "/conversations",
+            "/contacts",
+        ] {
+            match bind_http_path(path, true, false) {
+                Ok(_) => {}
+                Err(e) => {
+                    print_to_terminal(0, format!("testing: http: {:?}", e,).as_str());
+                }
+            }
+        }
+        if state.settings.public_read {
+            if let Err(e) = bind_http_path("/public/messages", false, false) {
                 print_to_terminal(0, format!("testing: http: {:?}", e,).as_str());
             }
         }
@@ -319,12 +244,21 @@ impl Guest for Component {
         // bind_http_path("/assets/*", true, false).unwrap();
 
         loop {
-            match handle_message(&our, &mut message_archive, &mut channel_id) {
+            match handle_message(&our, &mut state) {
                 Ok(()) => {}
                 Err(e) => {
                     print_to_terminal(0, format!("testing: error: {:?}", e,).as_str());
                 }
             };
+            if let Err(e) = retry_pending(&our, &mut state) {
+                print_to_terminal(0, format!("testing: retry: {:?}", e,).as_str());
+            }
+            if let Err(e) = deliver_scheduled(&our, &mut state) {
+                print_to_terminal(0, format!("testing: schedule: {:?}", e,).as_str());
+            }
+            if let Err(e) = ping_counterparties(&our, &mut state) {
+                print_to_terminal(0, format!("testing: presence: {:?}", e,).as_str());
+            }
         }
     }
 }
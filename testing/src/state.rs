@@ -0,0 +1,1079 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::{self};
+use serde::{Deserialize, Serialize};
+use uqbar_process_lib::{
+    print_to_terminal,
+    vfs::{create_drive, open_file},
+    Address,
+};
+
+use crate::chat::*;
+
+/// Keys agreed with one counterparty, by version. Old versions are kept so messages
+/// sealed before a rotation can still be opened.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChatKeys {
+    pub(crate) current: u32,
+    pub(crate) keys: HashMap<u32, [u8; 32]>,
+}
+
+pub(crate) type MessageArchive = HashMap<String, Vec<ChatMessage>>;
+
+/// A backup of every chat, as downloaded from `/export`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ArchiveExport {
+    pub(crate) node: String,
+    pub(crate) exported_at: u64,
+    pub(crate) message_archive: MessageArchive,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct ReadMarker {
+    /// Newest message in the chat that we have read
+    pub(crate) by_us: Option<MessageId>,
+    /// Newest message in the chat that the counterparty has read
+    pub(crate) by_them: Option<MessageId>,
+}
+
+/// Everything we persist to the VFS between restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    pub(crate) message_archive: MessageArchive,
+    #[serde(default)]
+    pub(crate) last_read: HashMap<String, ReadMarker>,
+    /// Pinned message ids per chat, in the order they were pinned
+    #[serde(default)]
+    pub(crate) pinned: HashMap<String, Vec<MessageId>>,
+    /// Counter for the ids of messages created on this node
+    #[serde(default)]
+    pub(crate) next_message_id: u64,
+    /// Messages from others stored since we last marked each chat read
+    #[serde(default)]
+    pub(crate) unread: HashMap<String, u32>,
+    /// Unsent text per chat, cleared once a message to that chat goes out
+    #[serde(default)]
+    pub(crate) drafts: HashMap<String, String>,
+    /// Display names we've given other nodes. Like drafts, these never leave this node.
+    #[serde(default)]
+    pub(crate) aliases: HashMap<String, String>,
+    /// Nodes we've saved as contacts; their nicknames are kept in `aliases`
+    #[serde(default)]
+    pub(crate) contacts: HashSet<String>,
+    /// Chats we aren't notified about, with when the mute ends, if it does
+    #[serde(default)]
+    pub(crate) muted: HashMap<String, Option<u64>>,
+    /// Member nodes of each room we're in, ourselves included
+    #[serde(default)]
+    pub(crate) rooms: HashMap<String, Vec<String>>,
+    /// Names of the rooms that are invite-only groups, by id
+    #[serde(default)]
+    pub(crate) groups: HashMap<String, String>,
+    /// Notification settings per chat, for those that don't use the defaults
+    #[serde(default)]
+    pub(crate) chat_settings: HashMap<String, ChatSettings>,
+    /// Owners and admins of each group; other members are plain members
+    #[serde(default)]
+    pub(crate) group_roles: HashMap<String, HashMap<String, GroupRole>>,
+    /// Chats that no longer take new messages from either side
+    #[serde(default)]
+    pub(crate) closed: HashSet<String>,
+    /// Chats hidden from the main list until something new arrives in them
+    #[serde(default)]
+    pub(crate) archived: HashSet<String>,
+    /// Sequence number of the next message we send to each counterparty
+    #[serde(default)]
+    pub(crate) next_seq: HashMap<String, u64>,
+    /// Highest sequence number seen from each counterparty
+    #[serde(default)]
+    pub(crate) highest_seq: HashMap<String, u64>,
+    /// Sequence numbers skipped by each counterparty that we've asked for again
+    #[serde(default)]
+    pub(crate) missing_seqs: HashMap<String, Vec<u64>>,
+    /// Recently used client keys per chat, oldest first
+    #[serde(default)]
+    pub(crate) client_keys: HashMap<String, VecDeque<SeenClientKey>>,
+    /// Keys for the chats we encrypt, by counterparty
+    #[serde(default)]
+    pub(crate) chat_keys: HashMap<String, ChatKeys>,
+    /// Messages to send later, in the order they were scheduled
+    #[serde(default)]
+    pub(crate) scheduled: Vec<ScheduledMessage>,
+    #[serde(default)]
+    pub(crate) next_scheduled_id: u64,
+    /// Our messages waiting to be sent again
+    #[serde(default)]
+    pub(crate) pending: Vec<PendingMessage>,
+    /// Every open WebSocket, with the one chat it wants new messages from if it's said.
+    /// Everything but new messages is pushed to all of them.
+    #[serde(skip)]
+    pub(crate) channels: HashMap<u32, Option<String>>,
+    /// Our own process id, which this app has on other nodes too. Set at init.
+    #[serde(skip)]
+    pub(crate) process: String,
+    /// Our node name, which the messages we sign are signed as. Set at init.
+    #[serde(skip)]
+    pub(crate) node: String,
+    /// The key we sign messages with, made at first start
+    #[serde(default)]
+    pub(crate) signing_key: Option<[u8; 32]>,
+    /// The key each node signed with the first time, which later signatures must match
+    #[serde(default)]
+    pub(crate) verifying_keys: HashMap<String, [u8; 32]>,
+    /// Whether each node answered last time we tried it. Only kept while we're running.
+    #[serde(skip)]
+    pub(crate) presence: HashMap<String, bool>,
+    /// When each node last answered us
+    #[serde(default)]
+    pub(crate) last_seen: HashMap<String, u64>,
+    /// When we last did anything ourselves, which contacts may ask for if we share it
+    #[serde(default)]
+    pub(crate) last_active: u64,
+    /// What each node told us of when it was last active
+    #[serde(default)]
+    pub(crate) their_last_seen: HashMap<String, LastSeen>,
+    /// When the nodes we chat with are next pinged
+    #[serde(skip)]
+    pub(crate) next_ping: u64,
+    /// When each node's recent messages arrived, for the rate limit
+    #[serde(skip)]
+    pub(crate) rate_state: HashMap<String, Vec<u64>>,
+    /// When init ran, for uptime
+    #[serde(skip)]
+    pub(crate) started_at: u64,
+    /// Running totals per chat, counted from the archive at load and kept up to date
+    #[serde(skip)]
+    pub(crate) stats: HashMap<String, ChatStats>,
+    /// Saved alongside the rest of the state under the same keys as before it was grouped
+    #[serde(flatten)]
+    pub(crate) settings: Settings,
+    /// Nodes whose messages we drop
+    #[serde(default)]
+    pub(crate) blocked: HashSet<String>,
+    /// First messages from nodes we don't know yet, held until we accept or reject the chat
+    #[serde(default)]
+    pub(crate) message_requests: HashMap<String, Vec<ChatMessage>>,
+    /// Nodes whose message requests we rejected; dropped like blocked nodes
+    #[serde(default)]
+    pub(crate) ignored: HashSet<String>,
+    #[serde(default)]
+    pub(crate) filter: ContentFilter,
+}
+
+/// Tunables that can be changed at runtime. Unset limits fall back to the defaults.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Settings {
+    /// How long to wait on another node before a message counts as undelivered
+    #[serde(default)]
+    pub(crate) send_timeout_secs: Option<u64>,
+    /// The longest message content we accept
+    #[serde(default)]
+    pub(crate) max_message_length: Option<usize>,
+    /// The largest attachment we accept, in bytes
+    #[serde(default)]
+    pub(crate) max_attachment_size: Option<usize>,
+    /// How many earlier versions of an edited message we keep
+    #[serde(default)]
+    pub(crate) max_edit_history: Option<usize>,
+    /// How much of each chat to keep; everything when unset
+    #[serde(default)]
+    pub(crate) retention: Option<Retention>,
+    /// Serve a read-only copy of `/messages` at `/public/messages` without login, for kiosks
+    #[serde(default)]
+    pub(crate) public_read: bool,
+    /// Messages per frame when streaming history to a newly opened WebSocket
+    #[serde(default)]
+    pub(crate) history_batch_size: Option<usize>,
+    /// Sign the messages we send so their recipients can tell they're really from us
+    #[serde(default)]
+    pub(crate) sign_messages: bool,
+    /// Also keep each message as it was written, unescaped, for a UI trusted to render it safely
+    #[serde(default)]
+    pub(crate) trusted_content: bool,
+    /// Hold messages from nodes that aren't contacts and that we've never chatted with
+    /// until we accept the chat
+    #[serde(default)]
+    pub(crate) hold_message_requests: bool,
+    /// Tell contacts who ask when we were last active; everyone else is always told it's hidden
+    #[serde(default)]
+    pub(crate) share_last_seen: bool,
+    /// How often to ping the nodes we chat with to see who's reachable
+    #[serde(default)]
+    pub(crate) presence_interval_secs: Option<u64>,
+    /// How many messages another node may send us per `rate_limit_window_secs`
+    #[serde(default)]
+    pub(crate) rate_limit_messages: Option<usize>,
+    #[serde(default)]
+    pub(crate) rate_limit_window_secs: Option<u64>,
+}
+
+impl Settings {
+    /// Why these settings can't be used, if they can't
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if let Some(timeout) = self.send_timeout_secs {
+            if timeout == 0 || timeout > MAX_SEND_TIMEOUT_SECS {
+                return Err(format!(
+                    "send_timeout_secs must be between 1 and {}",
+                    MAX_SEND_TIMEOUT_SECS
+                ));
+            }
+        }
+        if self.max_message_length == Some(0) {
+            return Err("max_message_length must be at least 1".to_string());
+        }
+        if let Some(size) = self.max_attachment_size {
+            if size == 0 || size > ATTACHMENT_SIZE_LIMIT {
+                return Err(format!(
+                    "max_attachment_size must be between 1 and {}",
+                    ATTACHMENT_SIZE_LIMIT
+                ));
+            }
+        }
+        if self.history_batch_size == Some(0) {
+            return Err("history_batch_size must be at least 1".to_string());
+        }
+        if self.presence_interval_secs == Some(0) {
+            return Err("presence_interval_secs must be at least 1".to_string());
+        }
+        if self.rate_limit_messages == Some(0) {
+            return Err("rate_limit_messages must be at least 1".to_string());
+        }
+        if self.rate_limit_window_secs == Some(0) {
+            return Err("rate_limit_window_secs must be at least 1".to_string());
+        }
+        match self.retention {
+            Some(Retention::MaxMessages(0)) => {
+                Err("retention must keep at least one message".to_string())
+            }
+            Some(Retention::MaxAgeSecs(0)) => {
+                Err("retention must keep messages for at least a second".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How a chat gets our attention. Chats we've never set these for use the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ChatSettings {
+    #[serde(default)]
+    pub(crate) notify: Notify,
+    #[serde(default = "sound_default")]
+    pub(crate) sound: bool,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        ChatSettings {
+            notify: Notify::default(),
+            sound: sound_default(),
+        }
+    }
+}
+
+fn sound_default() -> bool {
+    true
+}
+
+/// Which new messages in a chat notify us
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Notify {
+    #[default]
+    All,
+    /// Only messages that `@` us
+    MentionsOnly,
+    None,
+}
+
+/// Totals for one chat. Deleted messages aren't counted but still bound the time span.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct ChatStats {
+    pub(crate) messages: usize,
+    pub(crate) characters: usize,
+    pub(crate) first_timestamp: Option<u64>,
+    pub(crate) last_timestamp: Option<u64>,
+    pub(crate) by_author: HashMap<String, usize>,
+}
+
+impl ChatStats {
+    pub(crate) fn add(&mut self, message: &ChatMessage) {
+        self.first_timestamp = Some(
+            self.first_timestamp
+                .map_or(message.timestamp, |first| first.min(message.timestamp)),
+        );
+        self.last_timestamp = Some(
+            self.last_timestamp
+                .map_or(message.timestamp, |last| last.max(message.timestamp)),
+        );
+        if message.deleted {
+            return;
+        }
+        self.messages += 1;
+        self.characters += message.content.chars().count();
+        *self.by_author.entry(message.author.clone()).or_default() += 1;
+    }
+
+    /// Take back a message that has just been deleted
+    pub(crate) fn remove(&mut self, message: &ChatMessage) {
+        self.messages = self.messages.saturating_sub(1);
+        self.characters = self
+            .characters
+            .saturating_sub(message.content.chars().count());
+        if let Some(count) = self.by_author.get_mut(&message.author) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// Limits each chat's history, dropping the oldest messages first
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum Retention {
+    MaxMessages(usize),
+    MaxAgeSecs(u64),
+}
+
+/// Checks every message we send or receive for unwanted words
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ContentFilter {
+    /// Lowercase, kept sorted
+    pub(crate) words: Vec<String>,
+    pub(crate) action: FilterAction,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum FilterAction {
+    /// Refuse the message
+    Reject,
+    /// Replace the filtered words with asterisks
+    #[default]
+    Censor,
+    /// Keep the message as is, marked for the UI
+    Flag,
+}
+
+impl ContentFilter {
+    /// `content` with filtered words starred out, or `None` if it has none
+    pub(crate) fn censor(&self, content: &str) -> Option<String> {
+        let mut censored = String::with_capacity(content.len());
+        let mut found = false;
+        let mut rest = content;
+        while let Some(start) = rest.find(char::is_alphanumeric) {
+            censored.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if self.words.contains(&word.to_lowercase()) {
+                found = true;
+                censored.push_str(&"*".repeat(word.chars().count()));
+            } else {
+                censored.push_str(word);
+            }
+            rest = &rest[end..];
+        }
+        censored.push_str(rest);
+
+        found.then_some(censored)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SeenClientKey {
+    author: String,
+    key: String,
+    message_id: MessageId,
+}
+
+/// Client keys remembered per chat before the oldest are forgotten
+const CLIENT_KEY_CACHE_SIZE: usize = 64;
+
+pub(crate) enum SeqCheck {
+    New,
+    Duplicate,
+    Gap { from_seq: u64, to_seq: u64 },
+}
+
+/// How long to wait on another node by default, in seconds
+const SEND_TIMEOUT_SECS: u64 = 5;
+
+/// Longest we let anyone configure the send timeout, in seconds
+const MAX_SEND_TIMEOUT_SECS: u64 = 300;
+
+/// Longest message content we store by default, in bytes
+const MAX_MESSAGE_LENGTH: usize = 8 * 1024;
+
+/// Largest attachment we accept by default, in bytes
+const MAX_ATTACHMENT_SIZE: usize = 10 * 1024 * 1024;
+
+/// Largest we let anyone configure attachments to be, in bytes
+const ATTACHMENT_SIZE_LIMIT: usize = 100 * 1024 * 1024;
+
+/// Earlier versions kept per edited message by default
+const MAX_EDIT_HISTORY: usize = 20;
+
+/// Messages per WebSocket frame when streaming history by default
+const HISTORY_BATCH_SIZE: usize = 100;
+
+/// How often the nodes we chat with are pinged by default, in seconds
+const PRESENCE_INTERVAL_SECS: u64 = 60;
+
+/// Messages another node may send us per window by default
+const RATE_LIMIT_MESSAGES: usize = 30;
+
+/// The window the rate limit counts over by default, in seconds
+const RATE_LIMIT_WINDOW_SECS: u64 = 60;
+
+impl State {
+    /// Ids are namespaced by author so both nodes in a chat can generate them concurrently
+    pub(crate) fn new_message_id(&mut self, author: &str) -> MessageId {
+        self.next_message_id += 1;
+        format!("{}:{}", author, self.next_message_id)
+    }
+
+    /// The open WebSockets that want new messages from `chat`
+    pub(crate) fn channels_for(&self, chat: &str) -> Vec<u32> {
+        self.channels
+            .iter()
+            .filter(|(_, filter)| filter.as_ref().is_none_or(|filter| filter == chat))
+            .map(|(channel_id, _)| *channel_id)
+            .collect()
+    }
+
+    pub(crate) fn chat_settings(&self, chat: &str) -> ChatSettings {
+        self.chat_settings.get(chat).copied().unwrap_or_default()
+    }
+
+    /// Whether `chat` is muted, forgetting the mute if it has run out
+    pub(crate) fn is_muted(&mut self, chat: &str) -> bool {
+        match self.muted.get(chat) {
+            Some(Some(until)) if *until <= now() => {
+                self.muted.remove(chat);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    pub(crate) fn new_seq(&mut self, counterparty: &str) -> u64 {
+        let next_seq = self.next_seq.entry(counterparty.to_string()).or_insert(1);
+        let seq = *next_seq;
+        *next_seq += 1;
+        seq
+    }
+
+    /// Record a sequence number from `sender`, reporting whether it's new, already seen,
+    /// or skips ahead of messages we never got
+    pub(crate) fn track_seq(&mut self, sender: &str, seq: u64) -> SeqCheck {
+        let missing = self.missing_seqs.entry(sender.to_string()).or_default();
+        if let Some(position) = missing.iter().position(|missing| *missing == seq) {
+            missing.remove(position);
+            return SeqCheck::New;
+        }
+
+        let highest = self.highest_seq.entry(sender.to_string()).or_insert(0);
+        if seq <= *highest {
+            return SeqCheck::Duplicate;
+        }
+        let from_seq = *highest + 1;
+        *highest = seq;
+        if seq == from_seq {
+            return SeqCheck::New;
+        }
+        missing.extend(from_seq..seq);
+        SeqCheck::Gap {
+            from_seq,
+            to_seq: seq - 1,
+        }
+    }
+
+    /// The message an author already sent to `chat` under this client key, if any
+    pub(crate) fn seen_client_key(
+        &self,
+        chat: &str,
+        author: &str,
+        key: &str,
+    ) -> Option<&MessageId> {
+        self.client_keys
+            .get(chat)?
+            .iter()
+            .find(|seen| seen.author == author && seen.key == key)
+            .map(|seen| &seen.message_id)
+    }
+
+    pub(crate) fn remember_client_key(
+        &mut self,
+        chat: &str,
+        author: &str,
+        key: &str,
+        message_id: &str,
+    ) {
+        let seen = self.client_keys.entry(chat.to_string()).or_default();
+        if seen.len() >= CLIENT_KEY_CACHE_SIZE {
+            seen.pop_front();
+        }
+        seen.push_back(SeenClientKey {
+            author: author.to_string(),
+            key: key.to_string(),
+            message_id: message_id.to_string(),
+        });
+    }
+
+    pub(crate) fn send_timeout(&self) -> u64 {
+        self.settings.send_timeout_secs.unwrap_or(SEND_TIMEOUT_SECS)
+    }
+
+    pub(crate) fn max_message_length(&self) -> usize {
+        self.settings
+            .max_message_length
+            .unwrap_or(MAX_MESSAGE_LENGTH)
+    }
+
+    pub(crate) fn max_attachment_size(&self) -> usize {
+        self.settings
+            .max_attachment_size
+            .unwrap_or(MAX_ATTACHMENT_SIZE)
+    }
+
+    pub(crate) fn max_edit_history(&self) -> usize {
+        self.settings.max_edit_history.unwrap_or(MAX_EDIT_HISTORY)
+    }
+
+    pub(crate) fn history_batch_size(&self) -> usize {
+        self.settings
+            .history_batch_size
+            .unwrap_or(HISTORY_BATCH_SIZE)
+    }
+
+    pub(crate) fn presence_interval(&self) -> u64 {
+        self.settings
+            .presence_interval_secs
+            .unwrap_or(PRESENCE_INTERVAL_SECS)
+    }
+
+    /// Count a message from `node` against its limit, unless it's already used it up
+    pub(crate) fn rate_limited(&mut self, node: &str) -> bool {
+        let limit = self
+            .settings
+            .rate_limit_messages
+            .unwrap_or(RATE_LIMIT_MESSAGES);
+        let window = self
+            .settings
+            .rate_limit_window_secs
+            .unwrap_or(RATE_LIMIT_WINDOW_SECS);
+        let now = now();
+
+        let recent = self.rate_state.entry(node.to_string()).or_default();
+        recent.retain(|timestamp| now.saturating_sub(*timestamp) < window);
+        if recent.len() >= limit {
+            return true;
+        }
+        recent.push(now);
+        false
+    }
+
+    /// Give messages stored before ids existed one, so they can be edited and deleted
+    fn assign_missing_ids(&mut self) {
+        let mut next_message_id = self.next_message_id;
+        for message in self.message_archive.values_mut().flatten() {
+            if message.id.is_empty() {
+                next_message_id += 1;
+                message.id = format!("{}:{}", message.author, next_message_id);
+            }
+        }
+        self.next_message_id = next_message_id;
+    }
+
+    /// Move everything kept under the chat `old` to `new`, merging into what `new` already
+    /// has. Where both have a setting, like an alias or draft, `new`'s is kept.
+    pub(crate) fn rename_chat(&mut self, old: &str, new: &str) {
+        if let Some(moved) = self.message_archive.remove(old) {
+            let messages = self.message_archive.entry(new.to_string()).or_default();
+            for message in moved {
+                if !messages.iter().any(|m| m.id == message.id) {
+                    messages.push(message);
+                }
+            }
+            messages.sort_by_key(|message| message.timestamp);
+        }
+        if let Some(unread) = self.unread.remove(old) {
+            *self.unread.entry(new.to_string()).or_default() += unread;
+        }
+        if let Some(pinned) = self.pinned.remove(old) {
+            let merged = self.pinned.entry(new.to_string()).or_default();
+            for id in pinned {
+                if !merged.contains(&id) {
+                    merged.push(id);
+                }
+            }
+        }
+
+        if let Some(held) = self.message_requests.remove(old) {
+            self.message_requests
+                .entry(new.to_string())
+                .or_default()
+                .extend(held);
+        }
+        if let Some(next_seq) = self.next_seq.remove(old) {
+            let merged = self.next_seq.entry(new.to_string()).or_default();
+            *merged = (*merged).max(next_seq);
+        }
+
+        fn rekey<T>(map: &mut HashMap<String, T>, old: &str, new: &str) {
+            if let Some(value) = map.remove(old) {
+                map.entry(new.to_string()).or_insert(value);
+            }
+        }
+        rekey(&mut self.last_read, old, new);
+        rekey(&mut self.aliases, old, new);
+        rekey(&mut self.drafts, old, new);
+        rekey(&mut self.muted, old, new);
+        rekey(&mut self.chat_settings, old, new);
+        rekey(&mut self.highest_seq, old, new);
+        rekey(&mut self.missing_seqs, old, new);
+        rekey(&mut self.client_keys, old, new);
+        rekey(&mut self.chat_keys, old, new);
+        rekey(&mut self.verifying_keys, old, new);
+        rekey(&mut self.presence, old, new);
+        rekey(&mut self.last_seen, old, new);
+        rekey(&mut self.their_last_seen, old, new);
+        rekey(&mut self.rate_state, old, new);
+
+        for set in [
+            &mut self.contacts,
+            &mut self.archived,
+            &mut self.closed,
+            &mut self.blocked,
+            &mut self.ignored,
+        ] {
+            if set.remove(old) {
+                set.insert(new.to_string());
+            }
+        }
+
+        // Queued and scheduled messages go to the new name
+        for pending in self
+            .pending
+            .iter_mut()
+            .filter(|pending| pending.chat == old)
+        {
+            pending.chat = new.to_string();
+        }
+        for scheduled in self
+            .scheduled
+            .iter_mut()
+            .filter(|scheduled| scheduled.target == old)
+        {
+            scheduled.target = new.to_string();
+        }
+        for filter in self.channels.values_mut() {
+            if filter.as_deref() == Some(old) {
+                *filter = Some(new.to_string());
+            }
+        }
+
+        self.recount_stats(old);
+        self.recount_stats(new);
+    }
+
+    /// Forget the chat with `chat`: its messages and everything kept about them, along with
+    /// our settings for it and whatever of ours is still waiting to go out to it. What we know
+    /// of the node itself stays, like its keys, sequence numbers and whether it's a contact,
+    /// so a new chat with it picks up where this one left off. Whether there was a chat.
+    pub(crate) fn delete_chat(&mut self, chat: &str) -> bool {
+        if self.message_archive.remove(chat).is_none() {
+            return false;
+        }
+        self.last_read.remove(chat);
+        self.pinned.remove(chat);
+        self.unread.remove(chat);
+        self.drafts.remove(chat);
+        self.stats.remove(chat);
+        self.muted.remove(chat);
+        self.chat_settings.remove(chat);
+        self.client_keys.remove(chat);
+        self.message_requests.remove(chat);
+        self.closed.remove(chat);
+        self.archived.remove(chat);
+        self.pending.retain(|pending| pending.chat != chat);
+        self.scheduled.retain(|scheduled| scheduled.target != chat);
+        true
+    }
+
+    /// Count `chat` again from scratch, after messages were dropped in bulk
+    pub(crate) fn recount_stats(&mut self, chat: &str) {
+        match self.message_archive.get(chat) {
+            Some(messages) => {
+                let mut stats = ChatStats::default();
+                for message in messages {
+                    stats.add(message);
+                }
+                self.stats.insert(chat.to_string(), stats);
+            }
+            None => {
+                self.stats.remove(chat);
+            }
+        }
+    }
+
+    fn recount_all_stats(&mut self) {
+        let chats: Vec<String> = self.message_archive.keys().cloned().collect();
+        self.stats.clear();
+        for chat in chats {
+            self.recount_stats(&chat);
+        }
+    }
+
+    /// Merge an exported archive into ours, adding only messages we don't have by id.
+    /// Returns how many were added.
+    pub(crate) fn import(&mut self, message_archive: MessageArchive) -> usize {
+        let mut added = 0;
+        for (counterparty, imported) in message_archive {
+            let messages = self.message_archive.entry(counterparty).or_default();
+            let before = messages.len();
+            for message in imported {
+                if message.id.is_empty() || !messages.iter().any(|m| m.id == message.id) {
+                    messages.push(message);
+                }
+            }
+            if messages.len() > before {
+                added += messages.len() - before;
+                messages.sort_by_key(|message| message.timestamp);
+            }
+        }
+
+        // New ids must not collide with imported ones
+        let highest_id = self
+            .message_archive
+            .values()
+            .flatten()
+            .filter_map(|message| message.id.rsplit_once(':'))
+            .filter_map(|(_, n)| n.parse::<u64>().ok())
+            .max()
+            .unwrap_or_default();
+        self.next_message_id = self.next_message_id.max(highest_id);
+        self.assign_missing_ids();
+        self.recount_all_stats();
+
+        added
+    }
+}
+
+const ARCHIVE_FILE: &str = "chat_archive.json";
+
+fn archive_path(our: &Address) -> anyhow::Result<String> {
+    let drive = create_drive(our.package_id(), "chat")?;
+    Ok(format!("{}/{}", drive, ARCHIVE_FILE))
+}
+
+pub(crate) fn save_state(our: &Address, state: &State) -> anyhow::Result<()> {
+    let file = open_file(&archive_path(our)?, true)?;
+    file.write(&serde_json::to_vec(state)?)?;
+    Ok(())
+}
+
+/// Read a saved archive back, including one saved before read markers existed
+fn parse_state(bytes: &[u8]) -> anyhow::Result<State> {
+    match serde_json::from_slice::<State>(bytes) {
+        Ok(state) => Ok(state),
+        // Archives saved before read markers existed hold only the messages
+        Err(e) => match serde_json::from_slice::<MessageArchive>(bytes) {
+            Ok(message_archive) => Ok(State {
+                message_archive,
+                ..State::default()
+            }),
+            Err(_) => Err(e.into()),
+        },
+    }
+}
+
+pub(crate) fn load_state(our: &Address) -> State {
+    let bytes = match archive_path(our).and_then(|path| open_file(&path, false)?.read()) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            print_to_terminal(0, &format!("testing: no saved archive: {:?}", e));
+            return State::default();
+        }
+    };
+
+    let mut state = match parse_state(&bytes) {
+        Ok(state) => state,
+        Err(e) => {
+            print_to_terminal(0, &format!("testing: corrupt archive: {:?}", e));
+            return State::default();
+        }
+    };
+
+    state.assign_missing_ids();
+    state.recount_all_stats();
+    state
+}
+
+pub(crate) fn find_message_mut<'a>(
+    message_archive: &'a mut MessageArchive,
+    chat: &str,
+    message_id: &str,
+) -> Option<&'a mut ChatMessage> {
+    message_archive
+        .get_mut(chat)?
+        .iter_mut()
+        .find(|message| message.id == message_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, author: &str, timestamp: u64) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            author: author.to_string(),
+            content: format!("message {}", id),
+            timestamp,
+            ..ChatMessage::default()
+        }
+    }
+
+    #[test]
+    fn attachment_size_is_configurable_within_bounds() {
+        let mut state = State::default();
+        assert_eq!(state.max_attachment_size(), MAX_ATTACHMENT_SIZE);
+        state.settings.max_attachment_size = Some(1024);
+        assert!(state.settings.validate().is_ok());
+        assert_eq!(state.max_attachment_size(), 1024);
+
+        for size in [0, ATTACHMENT_SIZE_LIMIT + 1] {
+            let settings = Settings {
+                max_attachment_size: Some(size),
+                ..Settings::default()
+            };
+            assert!(settings.validate().is_err(), "{} was allowed", size);
+        }
+    }
+
+    #[test]
+    fn repeated_seqs_are_duplicates() {
+        let mut state = State::default();
+        assert!(matches!(state.track_seq("bob.uq", 1), SeqCheck::New));
+        assert!(matches!(state.track_seq("bob.uq", 1), SeqCheck::Duplicate));
+        assert!(matches!(
+            state.track_seq("bob.uq", 4),
+            SeqCheck::Gap {
+                from_seq: 2,
+                to_seq: 3
+            }
+        ));
+        // A missed one arriving late is new once, then a duplicate like any other
+        assert!(matches!(state.track_seq("bob.uq", 2), SeqCheck::New));
+        assert!(matches!(state.track_seq("bob.uq", 2), SeqCheck::Duplicate));
+        assert!(matches!(state.track_seq("bob.uq", 4), SeqCheck::Duplicate));
+        // Each sender counts on its own
+        assert!(matches!(state.track_seq("carol.uq", 1), SeqCheck::New));
+    }
+
+    #[test]
+    fn saved_state_loads_back_the_same() {
+        let mut state = State::default();
+        state.message_archive.insert(
+            "bob.uq".to_string(),
+            vec![message("bob.uq:1", "bob.uq", 10)],
+        );
+        state.message_requests.insert(
+            "eve.uq".to_string(),
+            vec![message("eve.uq:1", "eve.uq", 20)],
+        );
+        state.contacts.insert("bob.uq".to_string());
+        state.blocked.insert("mallory.uq".to_string());
+        state.unread.insert("bob.uq".to_string(), 1);
+        state.signing_key = Some([7; 32]);
+        state.settings.hold_message_requests = true;
+        state.settings.share_last_seen = true;
+        state.settings.send_timeout_secs = Some(30);
+
+        let saved = serde_json::to_vec(&state).unwrap();
+        let loaded = parse_state(&saved).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&state).unwrap()
+        );
+    }
+
+    #[test]
+    fn rename_moves_everything_kept_per_chat() {
+        let (old, new) = ("bob.uq", "robert.uq");
+        let mut state = State::default();
+        state
+            .message_archive
+            .insert(old.to_string(), vec![message("bob.uq:1", old, 10)]);
+        state
+            .last_read
+            .insert(old.to_string(), ReadMarker::default());
+        state
+            .pinned
+            .insert(old.to_string(), vec!["bob.uq:1".to_string()]);
+        state.unread.insert(old.to_string(), 2);
+        state.drafts.insert(old.to_string(), "hi".to_string());
+        state.aliases.insert(old.to_string(), "Bob".to_string());
+        state.muted.insert(old.to_string(), None);
+        state
+            .chat_settings
+            .insert(old.to_string(), ChatSettings::default());
+        state.next_seq.insert(old.to_string(), 5);
+        state.highest_seq.insert(old.to_string(), 4);
+        state.missing_seqs.insert(old.to_string(), vec![3]);
+        state.client_keys.insert(old.to_string(), VecDeque::new());
+        state.chat_keys.insert(
+            old.to_string(),
+            ChatKeys {
+                current: 1,
+                keys: HashMap::from([(1, [1; 32])]),
+            },
+        );
+        state.verifying_keys.insert(old.to_string(), [2; 32]);
+        state.presence.insert(old.to_string(), true);
+        state.last_seen.insert(old.to_string(), 10);
+        state
+            .their_last_seen
+            .insert(old.to_string(), LastSeen::Hidden);
+        state.rate_state.insert(old.to_string(), vec![10]);
+        state
+            .message_requests
+            .insert(old.to_string(), vec![message("bob.uq:2", old, 20)]);
+        for set in [
+            &mut state.contacts,
+            &mut state.archived,
+            &mut state.closed,
+            &mut state.blocked,
+            &mut state.ignored,
+        ] {
+            set.insert(old.to_string());
+        }
+        state.pending.push(PendingMessage {
+            chat: old.to_string(),
+            message_id: "our.uq:1".to_string(),
+            attempts: 1,
+            next_retry: 30,
+        });
+        state.scheduled.push(ScheduledMessage {
+            id: "1".to_string(),
+            target: old.to_string(),
+            message: "later".to_string(),
+            deliver_at: 40,
+        });
+        state.channels.insert(1, Some(old.to_string()));
+
+        state.rename_chat(old, new);
+
+        let keys = |value: serde_json::Value| -> Vec<String> {
+            value
+                .as_object()
+                .map(|map| map.keys().cloned().collect())
+                .unwrap_or_default()
+        };
+        let saved = serde_json::to_value(&state).unwrap();
+        for (field, value) in saved.as_object().unwrap() {
+            assert!(
+                !keys(value.clone()).contains(&old.to_string()),
+                "{} still has {}",
+                field,
+                old
+            );
+            assert!(
+                !value
+                    .as_array()
+                    .is_some_and(|items| items.contains(&serde_json::json!(old))),
+                "{} still has {}",
+                field,
+                old
+            );
+        }
+        assert_eq!(state.message_archive[new].len(), 1);
+        assert_eq!(state.unread[new], 2);
+        assert_eq!(state.next_seq[new], 5);
+        assert!(state.chat_keys.contains_key(new));
+        assert!(state.verifying_keys.contains_key(new));
+        assert!(state.presence.contains_key(new));
+        assert!(state.rate_state.contains_key(new));
+        assert!(state.stats.contains_key(new) && !state.stats.contains_key(old));
+        assert!(state.closed.contains(new) && state.blocked.contains(new));
+        assert_eq!(state.pending[0].chat, new);
+        assert_eq!(state.scheduled[0].target, new);
+        assert_eq!(state.channels[&1].as_deref(), Some(new));
+    }
+
+    #[test]
+    fn delete_forgets_the_chat_but_not_the_node() {
+        let chat = "bob.uq";
+        let mut state = State::default();
+        state
+            .message_archive
+            .insert(chat.to_string(), vec![message("bob.uq:1", chat, 10)]);
+        state
+            .last_read
+            .insert(chat.to_string(), ReadMarker::default());
+        state
+            .pinned
+            .insert(chat.to_string(), vec!["bob.uq:1".to_string()]);
+        state.unread.insert(chat.to_string(), 2);
+        state.drafts.insert(chat.to_string(), "hi".to_string());
+        state.muted.insert(chat.to_string(), None);
+        state
+            .chat_settings
+            .insert(chat.to_string(), ChatSettings::default());
+        state.client_keys.insert(chat.to_string(), VecDeque::new());
+        state.closed.insert(chat.to_string());
+        state.archived.insert(chat.to_string());
+        state.pending.push(PendingMessage {
+            chat: chat.to_string(),
+            message_id: "our.uq:1".to_string(),
+            attempts: 1,
+            next_retry: 30,
+        });
+        state.scheduled.push(ScheduledMessage {
+            id: "1".to_string(),
+            target: chat.to_string(),
+            message: "later".to_string(),
+            deliver_at: 40,
+        });
+        state.recount_stats(chat);
+        state.contacts.insert(chat.to_string());
+        state.next_seq.insert(chat.to_string(), 5);
+        state.verifying_keys.insert(chat.to_string(), [2; 32]);
+
+        assert!(state.delete_chat(chat));
+
+        assert!(!state.message_archive.contains_key(chat));
+        assert!(!state.last_read.contains_key(chat));
+        assert!(!state.pinned.contains_key(chat));
+        assert!(!state.unread.contains_key(chat));
+        assert!(!state.drafts.contains_key(chat));
+        assert!(!state.stats.contains_key(chat));
+        assert!(!state.muted.contains_key(chat));
+        assert!(!state.chat_settings.contains_key(chat));
+        assert!(!state.client_keys.contains_key(chat));
+        assert!(!state.closed.contains(chat) && !state.archived.contains(chat));
+        assert!(state.pending.is_empty() && state.scheduled.is_empty());
+        // A new chat with them carries on from the same sequence number and key
+        assert!(state.contacts.contains(chat));
+        assert_eq!(state.next_seq[chat], 5);
+        assert!(state.verifying_keys.contains_key(chat));
+
+        assert!(!state.delete_chat(chat));
+    }
+
+    #[test]
+    fn archive_from_before_read_markers_still_loads() {
+        let mut message_archive = MessageArchive::new();
+        message_archive.insert(
+            "bob.uq".to_string(),
+            vec![message("bob.uq:1", "bob.uq", 10)],
+        );
+
+        let loaded = parse_state(&serde_json::to_vec(&message_archive).unwrap()).unwrap();
+
+        assert_eq!(loaded.message_archive["bob.uq"].len(), 1);
+    }
+}